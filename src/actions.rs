@@ -0,0 +1,182 @@
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use twilight_http::request::AuditLogReason;
+use twilight_interactions::command::CommandModel;
+use twilight_model::application::interaction::{application_command::CommandData, Interaction};
+use twilight_model::channel::message::embed::EmbedField;
+use twilight_model::id::Id;
+use twilight_model::id::marker::{ChannelMarker, GuildMarker, MessageMarker, RoleMarker, UserMarker};
+use twilight_model::util::Timestamp;
+use twilight_util::builder::embed::EmbedBuilder;
+use twl_fw::response;
+
+use crate::appeals;
+use crate::business_logic::{get_guild, get_initiating_user, is_server_admin, post_to_modlog, update_config_by, Actor};
+use crate::commands::ShadowCommand;
+use crate::locks::{deny_sending, OverwriteSnapshot};
+use crate::state::{BotHandler, BotState};
+
+// every moderation action the bot takes goes through execute(), so that shadow mode can stop it in
+// one place.  in shadow mode the bot still decides what to do exactly as it normally would, but
+// instead of doing it, it posts what it would have done to the modlog channel.  putting things back
+// the way they were (/unlock, /release, timers running out) isn't stopped, since whatever it undoes
+// must have really happened.
+
+/// Automatic rules that can be put in shadow mode on their own with /shadow rule.
+pub(crate) const RULES: &[&str] = &["nickname_policy", "sanction_reapply"];
+
+pub(crate) enum Action<'a> {
+    DeleteMessage {channel_id: Id<ChannelMarker>, message_id: Id<MessageMarker>},
+    Ban {user_id: Id<UserMarker>, reason: &'a str},
+    Timeout {user_id: Id<UserMarker>, until: SystemTime},
+    SetNickname {user_id: Id<UserMarker>, nick: Option<&'a str>, reason: &'a str},
+    AddRole {user_id: Id<UserMarker>, role_id: Id<RoleMarker>, reason: &'a str},
+    SetRoles {user_id: Id<UserMarker>, roles: &'a [Id<RoleMarker>]},
+    LockChannel {channel_id: Id<ChannelMarker>, original: Option<OverwriteSnapshot>},
+    Slowmode {channel_id: Id<ChannelMarker>, seconds: u16},
+    DirectMessage {user_id: Id<UserMarker>, content: &'a str},
+}
+
+impl Action<'_> {
+    fn describe(&self, guild_id: Id<GuildMarker>) -> String {
+        match self {
+            Action::DeleteMessage {channel_id, message_id} => format!("Delete https://discord.com/channels/{}/{}/{}", guild_id, channel_id, message_id),
+            Action::Ban {user_id, reason} => format!("Ban <@{}>: {}", user_id, reason),
+            Action::Timeout {user_id, until} => format!("Time out <@{}> until <t:{}:f>", user_id, until.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)),
+            Action::SetNickname {user_id, nick: Some(nick), reason} => format!("Change <@{}>'s nickname to `{}`: {}", user_id, nick, reason),
+            Action::SetNickname {user_id, nick: None, reason} => format!("Reset <@{}>'s nickname: {}", user_id, reason),
+            Action::AddRole {user_id, role_id, reason} => format!("Give <@{}> <@&{}>: {}", user_id, role_id, reason),
+            Action::SetRoles {user_id, roles} => format!("Set <@{}>'s roles to {}", user_id, roles.iter().map(|role| format!("<@&{}>", role)).collect::<Vec<_>>().join(" ")),
+            Action::LockChannel {channel_id, ..} => format!("Stop @everyone sending messages in <#{}>", channel_id),
+            Action::Slowmode {channel_id, seconds} => format!("Set slowmode in <#{}> to {} seconds", channel_id, seconds),
+            Action::DirectMessage {user_id, content} => format!("DM <@{}>: {}", user_id, content),
+        }
+    }
+}
+
+/// Why an action in this guild, coming from `rule` if it's from an automatic rule, should only be
+/// simulated, or None if it should really happen.
+// kept out of the async functions below for the same reason as update_config()
+fn shadowed_because(state: &BotState, guild_id: Id<GuildMarker>, rule: Option<&str>) -> Option<String> {
+    let config = state.config.lock().unwrap();
+    let guild_config = config.get(guild_id.get().to_string().as_str())?;
+    if guild_config.get("shadow_mode").and_then(|shadow| shadow.as_bool()).unwrap_or(false) {
+        return Some("Shadow mode is on in this server.".to_owned());
+    }
+    let rule = rule?;
+    let shadowed = guild_config.get("shadow_rules")
+        .and_then(|rules| rules.as_array())
+        .is_some_and(|rules| rules.iter().any(|r| r.as_str() == Some(rule)));
+    shadowed.then(|| format!("The `{}` rule is in shadow mode.", rule))
+}
+
+/// If shadow mode applies, post what would have been done to the modlog channel as a simulation and
+/// return true.  execute() does this for single actions; this is for ones made up of many of them,
+/// like a lockdown, which are better simulated as a whole than one post per channel.
+pub(crate) async fn simulate_if_shadowed(handler: &BotHandler, guild_id: Id<GuildMarker>, rule: Option<&str>, actor: &Actor<'_>, description: impl FnOnce() -> String) -> anyhow::Result<bool> {
+    let Some(why) = shadowed_because(&handler.state, guild_id, rule) else {
+        return Ok(false);
+    };
+    let builder = EmbedBuilder::new()
+        .title("Simulated action")
+        .description(description())
+        .field(EmbedField {name: "Would have been done by".to_string(), value: actor.describe(), inline: false})
+        .field(EmbedField {name: "Not done because".to_string(), value: why, inline: false});
+    post_to_modlog(handler, guild_id, builder.build()).await?;
+    Ok(true)
+}
+
+/// Carry out an action, or if shadow mode applies, post it to the modlog channel as a simulation
+/// instead.  Returns whether it was really done.
+pub(crate) async fn execute(handler: &BotHandler, guild_id: Id<GuildMarker>, rule: Option<&str>, actor: &Actor<'_>, action: Action<'_>) -> anyhow::Result<bool> {
+    if simulate_if_shadowed(handler, guild_id, rule, actor, || action.describe(guild_id)).await? {
+        return Ok(false);
+    }
+
+    match action {
+        Action::DeleteMessage {channel_id, message_id} => {
+            handler.client.delete_message(channel_id, message_id).await?;
+        },
+        Action::Ban {user_id, reason} => {
+            let told = appeals::before_ban(handler, guild_id, user_id, reason).await;
+            let res = async {
+                handler.client.create_ban(guild_id, user_id).reason(reason)?.await?;
+                anyhow::Ok(())
+            }.await;
+            if res.is_err() && told {
+                appeals::ban_failed(&handler.state, guild_id, user_id);
+            }
+            res?;
+        },
+        Action::Timeout {user_id, until} => {
+            let until = Timestamp::from_secs(until.duration_since(UNIX_EPOCH)?.as_secs() as i64)?;
+            handler.client.update_guild_member(guild_id, user_id).communication_disabled_until(Some(until))?.await?;
+        },
+        Action::SetNickname {user_id, nick, reason} => {
+            handler.client.update_guild_member(guild_id, user_id).nick(nick)?.reason(reason)?.await?;
+        },
+        Action::AddRole {user_id, role_id, reason} => {
+            handler.client.add_guild_member_role(guild_id, user_id, role_id).reason(reason)?.await?;
+        },
+        Action::SetRoles {user_id, roles} => {
+            handler.client.update_guild_member(guild_id, user_id).roles(roles).await?;
+        },
+        Action::LockChannel {channel_id, original} => {
+            deny_sending(handler, guild_id, channel_id, original).await?;
+        },
+        Action::Slowmode {channel_id, seconds} => {
+            handler.client.update_channel(channel_id).rate_limit_per_user(seconds)?.await?;
+        },
+        Action::DirectMessage {user_id, content} => {
+            let dm_channel = handler.client.create_private_channel(user_id).await?.model().await?;
+            handler.client.create_message(dm_channel.id).content(content)?.await?;
+        },
+    }
+    Ok(true)
+}
+
+pub(crate) async fn shadow(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+
+    if !is_server_admin(&handler, &inter) {
+        response!(ephemeral; handler, inter, "You need the Manage Server permission to change the bot's settings.");
+        return Ok(());
+    }
+
+    match ShadowCommand::from_interaction(data.into())? {
+        ShadowCommand::Server(cmd) => {
+            update_config_by(&handler, moderator_user, guild_id, |guild_config| guild_config["shadow_mode"] = toml_edit::value(cmd.enabled)).await?;
+            if cmd.enabled {
+                response!(ephemeral; handler, inter, "Shadow mode is on.  Deletes, bans, timeouts, locks, quarantines and other moderation actions will be posted to the modlog channel instead of being carried out.");
+            } else {
+                response!(ephemeral; handler, inter, "Shadow mode is off.  Actions will be carried out for real.");
+            }
+        },
+        ShadowCommand::Rule(cmd) => {
+            if !RULES.contains(&cmd.rule.as_str()) {
+                response!(ephemeral; handler, inter, "There is no rule called `{}`.  Rules are: {}", cmd.rule, RULES.iter().map(|rule| format!("`{}`", rule)).collect::<Vec<_>>().join(", "));
+                return Ok(());
+            }
+            let message = update_config_by(&handler, moderator_user, guild_id, |guild_config| {
+                let Some(rules) = guild_config.as_table_mut().and_then(|table| table.entry("shadow_rules").or_insert(toml_edit::value(toml_edit::Array::new())).as_array_mut()) else {
+                    return "Config file is not in a valid format.  No changes were made.".to_owned();
+                };
+                let pos = rules.iter().position(|r| r.as_str() == Some(cmd.rule.as_str()));
+                match (cmd.enabled, pos) {
+                    (true, None) => rules.push(cmd.rule.as_str()),
+                    (false, Some(idx)) => {rules.remove(idx);},
+                    _ => {},
+                }
+                if cmd.enabled {
+                    format!("The `{}` rule is in shadow mode.  What it would do will be posted to the modlog channel instead of being carried out.", cmd.rule)
+                } else {
+                    format!("The `{}` rule is no longer in shadow mode.", cmd.rule)
+                }
+            }).await?;
+            response!(ephemeral; handler, inter, "{}", message);
+        },
+    }
+    Ok(())
+}
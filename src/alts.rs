@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Deserialize};
+use twilight_model::application::interaction::Interaction;
+use twilight_model::channel::message::component::{ActionRow, Button, ButtonStyle, Component};
+use twilight_model::channel::message::embed::EmbedField;
+use twilight_model::gateway::payload::incoming::{BanAdd, BanRemove, MemberAdd};
+use twilight_model::id::Id;
+use twilight_model::id::marker::UserMarker;
+use twilight_model::user::User;
+use twilight_util::builder::embed::EmbedBuilder;
+use twl_fw::response;
+
+use anyhow::anyhow;
+
+use smb_log_format::ModLogAction;
+use crate::actions::{self, Action};
+use crate::business_logic::{format_user, get_initiating_user, get_modlog_channel, is_allowed_to_use, resolve_buttons, Actor};
+use crate::disk_log::ModLogEntryExt;
+use crate::history::{self, CaseKind};
+use crate::names;
+use crate::persist::Persisted;
+use crate::state::{BotHandler, BotState};
+use crate::userinfo::created_at;
+
+/// A new account created this soon after a ban counts as suspicious.
+const CREATION_WINDOW: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// guild ID -> banned user ID -> what they looked like when they were banned
+static BANNED_PROFILES: Persisted<HashMap<u64, HashMap<u64, BannedProfile>>> = Persisted::new("banned_profiles.msgpack");
+
+#[derive(Serialize, Deserialize, Clone)]
+struct BannedProfile {
+    name: String,
+    global_name: Option<String>,
+    avatar: Option<String>,
+    banned_at: SystemTime,
+}
+
+/// Called for every GUILD_BAN_ADD.  Remembers what the banned user looked like so we can spot
+/// them if they come back on another account.
+pub(crate) async fn on_ban(state: Arc<BotState>, ban: BanAdd) {
+    let profile = BannedProfile {
+        name: ban.user.name.clone(),
+        global_name: ban.user.global_name.clone(),
+        avatar: ban.user.avatar.map(|hash| hash.to_string()),
+        banned_at: SystemTime::now(),
+    };
+    BANNED_PROFILES.update(&state, |profiles| profiles.entry(ban.guild_id.get()).or_default().insert(ban.user.id.get(), profile));
+}
+
+/// Called for every GUILD_BAN_REMOVE.
+pub(crate) async fn on_unban(state: Arc<BotState>, unban: BanRemove) {
+    BANNED_PROFILES.update(&state, |profiles| {
+        if let Some(guild) = profiles.get_mut(&unban.guild_id.get()) {
+            guild.remove(&unban.user.id.get());
+        }
+    });
+}
+
+/// Something about a new member that suggests they're a banned user on a different account.
+enum Signal {
+    SimilarName {new_name: String, old_name: String},
+    SameAvatar,
+    CreatedAfterBan,
+}
+
+impl Signal {
+    fn describe(&self) -> String {
+        match self {
+            Signal::SimilarName {new_name, old_name} => format!("Name \"{}\" looks like \"{}\"", new_name, old_name),
+            Signal::SameAvatar => "Identical avatar".to_owned(),
+            Signal::CreatedAfterBan => "Account created shortly after the ban".to_owned(),
+        }
+    }
+
+    /// Whether this is worth bothering anyone with on its own.  A creation date isn't.
+    fn is_strong(&self) -> bool {
+        !matches!(self, Signal::CreatedAfterBan)
+    }
+}
+
+/// Why a new member might be a banned user on a different account.
+fn signals(user: &User, profile: &BannedProfile) -> Vec<Signal> {
+    let mut signals = Vec::new();
+
+    let new_names = [Some(&user.name), user.global_name.as_ref()];
+    let old_names = [Some(&profile.name), profile.global_name.as_ref()];
+    'outer: for new_name in new_names.iter().flatten() {
+        for old_name in old_names.iter().flatten() {
+            if names::looks_like(new_name, old_name) {
+                signals.push(Signal::SimilarName {new_name: (*new_name).clone(), old_name: (*old_name).clone()});
+                break 'outer;
+            }
+        }
+    }
+
+    if let (Some(new_avatar), Some(old_avatar)) = (user.avatar.map(|hash| hash.to_string()), profile.avatar.as_ref()) {
+        if new_avatar == *old_avatar {
+            signals.push(Signal::SameAvatar);
+        }
+    }
+
+    let created = created_at(user.id);
+    if created >= profile.banned_at && created.duration_since(profile.banned_at).unwrap_or_default() < CREATION_WINDOW {
+        signals.push(Signal::CreatedAfterBan);
+    }
+
+    signals
+}
+
+/// Called for every GUILD_MEMBER_ADD.  Compares the new member against everyone banned from the
+/// guild and reports likely matches to the modlog channel.
+pub(crate) async fn on_member_add(handler: Arc<BotHandler>, add: MemberAdd) {
+    if let Err(e) = check_member(&handler, &add).await {
+        tracing::error!("Error checking {} for ban evasion: {}", add.member.user.id, e);
+    }
+}
+
+async fn check_member(handler: &BotHandler, add: &MemberAdd) -> anyhow::Result<()> {
+    let user = &add.member.user;
+    let Some(modlog_channel_id) = get_modlog_channel(&handler.state, add.guild_id) else {
+        return Ok(());
+    };
+
+    let matches = BANNED_PROFILES.read(&handler.state, |profiles| {
+        profiles.get(&add.guild_id.get()).map(|guild| {
+            guild.iter()
+                .map(|(banned_id, profile)| (*banned_id, signals(user, profile)))
+                .filter(|(_, signals)| signals.len() >= 2 || signals.iter().any(Signal::is_strong))
+                .collect::<Vec<_>>()
+        }).unwrap_or_default()
+    });
+
+    for (banned_id, signals) in matches {
+        let embed = EmbedBuilder::new()
+            .title("Possible ban evasion")
+            .field(EmbedField {name: "New member".to_string(), value: format_user(user), inline: false})
+            .field(EmbedField {name: "Resembles banned user".to_string(), value: format!("<@{}> ({})", banned_id, banned_id), inline: false})
+            .field(EmbedField {name: "Signals".to_string(), value: signals.iter().map(Signal::describe).collect::<Vec<_>>().join("\n"), inline: false})
+            .build();
+        let button = Component::ActionRow(ActionRow {
+            components: vec![Component::Button(Button {
+                custom_id: Some(format!("alt_ban:{}:{}", user.id, banned_id)),
+                disabled: false,
+                emoji: None,
+                label: Some("Ban".to_owned()),
+                style: ButtonStyle::Danger,
+                url: None,
+            })],
+        });
+        let _pending = handler.state.metrics.modlog_post();
+        handler.client.create_message(modlog_channel_id).embeds(&[embed])?.components(&[button])?.await?;
+    }
+    Ok(())
+}
+
+/// Called when a moderator presses Ban on a possible ban evasion report.
+pub(crate) async fn ban_alt(handler: Arc<BotHandler>, inter: Interaction, args: &str) -> anyhow::Result<()> {
+    let guild_id = inter.guild_id.ok_or(anyhow!("Ban button pressed outside of a guild"))?;
+    let moderator_user = get_initiating_user(&inter)?;
+
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "ban")) {
+        response!(ephemeral; handler, inter, "You do not have permission to use that command.");
+        return Ok(());
+    }
+
+    let (user_id, original_id) = args.split_once(':').ok_or(anyhow!("Malformed alt ban custom ID: {}", args))?;
+    let user_id: Id<UserMarker> = user_id.parse()?;
+    let original_id: Id<UserMarker> = original_id.parse()?;
+
+    // the signals were worked out when the member joined and only live in the report's embed now
+    let signals = inter.message.as_ref()
+        .and_then(|message| message.embeds.first())
+        .and_then(|embed| embed.fields.iter().find(|field| field.name == "Signals"))
+        .map(|field| field.value.lines().map(str::to_owned).collect::<Vec<_>>())
+        .unwrap_or_default();
+
+    let reason = format!("Ban evasion: alt account of {}", original_id);
+    if !actions::execute(&handler, guild_id, None, &Actor::User(moderator_user), Action::Ban {user_id, reason: &reason}).await? {
+        response!(ephemeral; handler, inter, "Shadow mode is on, so <@{}> was not banned.  What would have happened has been posted to the modlog channel.", user_id);
+        return Ok(());
+    }
+
+    Actor::User(moderator_user).log_entry(None, ModLogAction::BanEvasion {
+        user_id: user_id.get(),
+        original_user_id: original_id.get(),
+        signals,
+    }).log(&handler.state);
+    history::record(&handler.state, guild_id, user_id, Some(moderator_user.id), CaseKind::Ban, format!("Alt account of <@{}>", original_id));
+    history::record(&handler.state, guild_id, original_id, Some(moderator_user.id), CaseKind::Note, format!("Evaded their ban as <@{}>, who has also been banned", user_id));
+
+    let when = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    resolve_buttons(&handler, &inter, &format!("Banned by {} at <t:{}:f>", format_user(moderator_user), when)).await
+}
@@ -0,0 +1,323 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use serde::{Serialize, Deserialize};
+use twilight_http::request::AuditLogReason;
+use twilight_interactions::command::CommandModel;
+use twilight_model::application::interaction::{application_command::CommandData, modal::ModalInteractionData, Interaction};
+use twilight_model::channel::Message;
+use twilight_model::channel::message::component::{ActionRow, Button, ButtonStyle, Component, TextInput, TextInputStyle};
+use twilight_model::channel::message::embed::EmbedField;
+use twilight_model::gateway::payload::incoming::BanAdd;
+use twilight_model::id::Id;
+use twilight_model::id::marker::{GuildMarker, UserMarker};
+use twilight_model::user::User;
+use twilight_util::builder::embed::EmbedBuilder;
+use twl_fw::response;
+
+use anyhow::anyhow;
+
+use smb_log_format::{ModLogEntry, ModLogAction};
+use crate::business_logic::{bot_user, format_user, get_config_channel, get_guild, get_initiating_user, get_modlog_channel, guild_name, is_allowed_to_use, is_server_admin, modal_value, resolve_buttons, respond_with_modal, update_config_by};
+use crate::commands::AppealChannelCommand;
+use crate::history::{self, CaseKind};
+use crate::disk_log::ModLogEntryExt;
+use crate::persist::{Persisted, Scratch};
+use crate::state::{BotHandler, BotState};
+
+/// Every ban we've seen, keyed by (guild ID, user ID).
+static BANS: Persisted<HashMap<(u64, u64), BanCase>> = Persisted::new("bans.msgpack");
+
+/// Bans the bot is making itself, whose users were already told before the ban, so on_ban()
+/// shouldn't tell them again.  Keyed by (guild ID, user ID).
+static TOLD_BEFORE_BAN: Scratch<HashSet<(u64, u64)>> = Scratch::new("told_before_ban");
+
+#[derive(Serialize, Deserialize, Clone)]
+struct BanCase {
+    reason: String,
+    banned_at: SystemTime,
+    status: AppealStatus,
+}
+
+#[derive(Serialize, Deserialize, Clone, PartialEq, Eq)]
+enum AppealStatus {
+    /// The user has been told they can appeal but hasn't yet.
+    Open,
+    /// The user has appealed and the moderators haven't decided yet.
+    Submitted,
+    Approved,
+    Denied,
+}
+
+/// Called when a member is banned, by the bot or by anyone else.  DMs them the reason and a button
+/// to appeal, unless before_ban() already did.
+pub(crate) async fn on_ban(handler: Arc<BotHandler>, ban: BanAdd) {
+    if TOLD_BEFORE_BAN.update(&handler.state, |told| told.remove(&(ban.guild_id.get(), ban.user.id.get()))) {
+        return;
+    }
+    let res = async {
+        if get_config_channel(&handler.state, ban.guild_id, "appeals_channel_id").is_none() {
+            return Ok(());
+        }
+        let reason = handler.client.ban(ban.guild_id, ban.user.id).await?.model().await?.reason.unwrap_or_else(|| "No reason given.".to_owned());
+        notify_banned_user(&handler, ban.guild_id, &ban.user, reason).await
+    }.await;
+    if let Err(e) = res {
+        tracing::warn!("Couldn't tell {} about their ban from {}: {}", ban.user.id, ban.guild_id, e);
+    }
+}
+
+/// Called by actions::execute() just before the bot bans someone itself.  By the time on_ban()
+/// hears about a ban the user may no longer share a server with the bot, and then Discord won't
+/// let it DM them, so for the bans it makes itself the bot tells them while it still can.
+/// Returns whether they were told.
+pub(crate) async fn before_ban(handler: &BotHandler, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>, reason: &str) -> bool {
+    if get_config_channel(&handler.state, guild_id, "appeals_channel_id").is_none() {
+        return false;
+    }
+    let res = async {
+        let user = match handler.cache().user(user_id) {
+            Some(user) => user.value().clone(),
+            None => handler.client.user(user_id).await?.model().await?,
+        };
+        notify_banned_user(handler, guild_id, &user, reason.to_owned()).await
+    }.await;
+    match res {
+        Ok(()) => {
+            TOLD_BEFORE_BAN.update(&handler.state, |told| told.insert((guild_id.get(), user_id.get())));
+            true
+        },
+        Err(e) => {
+            // on_ban() will have another go once the ban has happened
+            tracing::warn!("Couldn't tell {} about their ban from {} before banning them: {}", user_id, guild_id, e);
+            false
+        },
+    }
+}
+
+/// Called by actions::execute() if a ban that before_ban() told the user about didn't go through,
+/// so that they can't appeal a ban they don't have.
+pub(crate) fn ban_failed(state: &BotState, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>) {
+    TOLD_BEFORE_BAN.update(state, |told| told.remove(&(guild_id.get(), user_id.get())));
+    BANS.update(state, |bans| bans.remove(&(guild_id.get(), user_id.get())));
+}
+
+async fn notify_banned_user(handler: &BotHandler, guild_id: Id<GuildMarker>, user: &User, reason: String) -> anyhow::Result<()> {
+    let banned_at = SystemTime::now();
+
+    let text = format!("You have been banned from {}.\nReason: {}\n\nIf you think this was a mistake, press the button below or reply to this message to appeal.", guild_name(handler, guild_id), reason);
+    let button = Component::ActionRow(ActionRow {
+        components: vec![Component::Button(Button {
+            custom_id: Some(format!("appeal:{}", guild_id)),
+            disabled: false,
+            emoji: None,
+            label: Some("Appeal".to_owned()),
+            style: ButtonStyle::Primary,
+            url: None,
+        })],
+    });
+    // if this fails they were never offered an appeal, so there's nothing to record
+    let dm_channel = handler.client.create_private_channel(user.id).await?.model().await?;
+    handler.client.create_message(dm_channel.id).content(&text)?.components(&[button])?.await?;
+
+    BANS.update(&handler.state, |bans| bans.insert((guild_id.get(), user.id.get()), BanCase {reason: reason.clone(), banned_at, status: AppealStatus::Open}));
+    ModLogEntry::new_by_bot(&bot_user(handler)?, None, banned_at, ModLogAction::AppealOffered {
+        user_id: user.id.get(),
+        user_name: user.name.clone(),
+        user_discrim: user.discriminator,
+        ban_reason: reason,
+    }).log(&handler.state);
+    Ok(())
+}
+
+/// Called for DMs that aren't part of a modmail ticket.  If the sender has a ban they haven't
+/// appealed yet, the message is taken as their appeal.  Returns whether the message was used.
+pub(crate) async fn on_direct_message(handler: &BotHandler, message: &Message) -> anyhow::Result<bool> {
+    let open_ban = BANS.read(&handler.state, |bans| {
+        bans.iter()
+            .find(|((_, user_id), case)| *user_id == message.author.id.get() && case.status == AppealStatus::Open)
+            .map(|((guild_id, _), _)| Id::new(*guild_id))
+    });
+    let Some(guild_id) = open_ban else {
+        return Ok(false);
+    };
+    submit_appeal(handler, guild_id, &message.author, &message.content).await?;
+    handler.client.create_message(message.channel_id).content("Your appeal has been sent to the moderators.")?.await?;
+    Ok(true)
+}
+
+/// Called when a banned user presses the Appeal button in their DMs.
+pub(crate) async fn appeal_button(handler: Arc<BotHandler>, inter: Interaction, args: &str) -> anyhow::Result<()> {
+    let appeal_input = TextInput {
+        custom_id: "appeal".into(),
+        label: "Why should you be unbanned?".into(),
+        max_length: Some(1000),
+        min_length: Some(1),
+        placeholder: None,
+        required: Some(true),
+        style: TextInputStyle::Paragraph,
+        value: None,
+    };
+    respond_with_modal(&handler, &inter, format!("appeal_submit:{}", args), "Ban appeal".into(), vec![appeal_input]).await
+}
+
+/// Called when a banned user submits the modal opened by [appeal_button].
+pub(crate) async fn appeal_modal(handler: Arc<BotHandler>, inter: Interaction, data: ModalInteractionData, args: &str) -> anyhow::Result<()> {
+    let user = get_initiating_user(&inter)?;
+    let guild_id: Id<GuildMarker> = args.parse()?;
+    let appeal = modal_value(&data, "appeal").unwrap_or_default();
+
+    let status = BANS.read(&handler.state, |bans| bans.get(&(guild_id.get(), user.id.get())).map(|case| case.status.clone()));
+    if status != Some(AppealStatus::Open) {
+        response!(handler, inter, "You have already appealed this ban.");
+        return Ok(());
+    }
+    submit_appeal(&handler, guild_id, user, appeal).await?;
+    response!(handler, inter, "Your appeal has been sent to the moderators.");
+    Ok(())
+}
+
+async fn submit_appeal(handler: &BotHandler, guild_id: Id<GuildMarker>, user: &User, appeal: &str) -> anyhow::Result<()> {
+    let appeals_channel_id = get_config_channel(&handler.state, guild_id, "appeals_channel_id").ok_or(anyhow!("Appeals are not configured in guild {}", guild_id))?;
+    let case = BANS.read(&handler.state, |bans| bans.get(&(guild_id.get(), user.id.get())).cloned())
+        .ok_or(anyhow!("No ban on record for {} in {}", user.id, guild_id))?;
+
+    let embed = EmbedBuilder::new()
+        .title("Ban appeal")
+        .description(appeal)
+        .field(EmbedField {name: "User".to_string(), value: format_user(user), inline: false})
+        .field(EmbedField {name: "Ban reason".to_string(), value: case.reason, inline: false})
+        .build();
+    let button = |decision: &str, label: &str, style| Component::Button(Button {
+        custom_id: Some(format!("appeal_decision:{}:{}:{}", decision, guild_id, user.id)),
+        disabled: false,
+        emoji: None,
+        label: Some(label.to_owned()),
+        style,
+        url: None,
+    });
+    let buttons = Component::ActionRow(ActionRow {
+        components: vec![
+            button("approve", "Approve", ButtonStyle::Success),
+            button("deny", "Deny", ButtonStyle::Danger),
+        ],
+    });
+    handler.client.create_message(appeals_channel_id).embeds(&[embed])?.components(&[buttons])?.await?;
+
+    // only once the moderators can see it, so a failed post leaves the user free to try again
+    BANS.update(&handler.state, |bans| {
+        if let Some(case) = bans.get_mut(&(guild_id.get(), user.id.get())) {
+            case.status = AppealStatus::Submitted;
+        }
+    });
+    ModLogEntry::new_by_bot(&bot_user(handler)?, None, SystemTime::now(), ModLogAction::AppealSubmitted {
+        user_id: user.id.get(),
+        user_name: user.name.clone(),
+        user_discrim: user.discriminator,
+        appeal: appeal.to_owned(),
+    }).log(&handler.state);
+    Ok(())
+}
+
+/// Called when a moderator presses Approve or Deny on an appeal.
+pub(crate) async fn appeal_decision(handler: Arc<BotHandler>, inter: Interaction, args: &str) -> anyhow::Result<()> {
+    let guild_id = inter.guild_id.ok_or(anyhow!("Appeal button pressed outside of a guild"))?;
+    let moderator_user = get_initiating_user(&inter)?;
+
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "appeals")) {
+        response!(ephemeral; handler, inter, "You do not have permission to use that command.");
+        return Ok(());
+    }
+
+    let mut args = args.split(':');
+    let (Some(decision), Some(_), Some(user_id)) = (args.next(), args.next(), args.next()) else {
+        return Err(anyhow!("Malformed appeal custom ID"));
+    };
+    let user_id: Id<UserMarker> = user_id.parse()?;
+    let approved = match decision {
+        "approve" => true,
+        "deny" => false,
+        other => return Err(anyhow!("Unknown appeal decision {}", other)),
+    };
+
+    let submitted = BANS.read(&handler.state, |bans| bans.get(&(guild_id.get(), user_id.get())).is_some_and(|case| case.status == AppealStatus::Submitted));
+    if !submitted {
+        response!(ephemeral; handler, inter, "This appeal has already been decided.");
+        return Ok(());
+    }
+
+    // unban before marking the appeal approved, so a failed unban leaves it to be decided again
+    if approved {
+        if let Err(e) = handler.client.delete_ban(guild_id, user_id).reason(&format!("Appeal approved by {}", moderator_user.name))?.await {
+            tracing::warn!("Couldn't unban {} from {}: {}", user_id, guild_id, e);
+            response!(ephemeral; handler, inter, "Couldn't unban them, so the appeal is still open.  Error was: {}", e);
+            return Ok(());
+        }
+    }
+
+    let decided = BANS.update(&handler.state, |bans| {
+        let case = bans.get_mut(&(guild_id.get(), user_id.get()))?;
+        if case.status != AppealStatus::Submitted {
+            return None;
+        }
+        case.status = if approved {AppealStatus::Approved} else {AppealStatus::Denied};
+        Some(())
+    });
+    if decided.is_none() {
+        response!(ephemeral; handler, inter, "This appeal has already been decided.");
+        return Ok(());
+    }
+
+    if approved {
+        ModLogEntry::new(moderator_user, None, SystemTime::now(), ModLogAction::AppealApproved {user_id: user_id.get()}).log(&handler.state);
+        history::record(&handler.state, guild_id, user_id, Some(moderator_user.id), CaseKind::Unban, "Appeal approved");
+    } else {
+        ModLogEntry::new(moderator_user, None, SystemTime::now(), ModLogAction::AppealDenied {user_id: user_id.get()}).log(&handler.state);
+    }
+
+    let outcome = if approved {"Appeal approved"} else {"Appeal denied"};
+    let text = if approved {
+        format!("Your appeal to {} has been approved.  You may rejoin the server.", guild_name(&handler, guild_id))
+    } else {
+        format!("Your appeal to {} has been denied.", guild_name(&handler, guild_id))
+    };
+    match handler.client.create_private_channel(user_id).await {
+        Ok(resp) => {
+            let dm_channel = resp.model().await?;
+            if let Err(e) = handler.client.create_message(dm_channel.id).content(&text)?.await {
+                tracing::warn!("Couldn't tell {} about their appeal: {}", user_id, e);
+            }
+        },
+        Err(e) => tracing::warn!("Couldn't tell {} about their appeal: {}", user_id, e),
+    }
+
+    if let Some(modlog_channel_id) = get_modlog_channel(&handler.state, guild_id) {
+        let builder = EmbedBuilder::new()
+            .title(outcome)
+            .field(EmbedField {name: "User".to_string(), value: format!("<@{}>", user_id), inline: false})
+            .field(EmbedField {name: "Decided by".to_string(), value: format_user(moderator_user), inline: false});
+        let _pending = handler.state.metrics.modlog_post();
+        handler.client.create_message(modlog_channel_id).embeds(&[builder.build()])?.await?;
+    }
+
+    resolve_buttons(&handler, &inter, &format!("{} by {}", outcome, format_user(moderator_user))).await
+}
+
+pub(crate) async fn appeal_channel(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+
+    if !is_server_admin(&handler, &inter) {
+        response!(ephemeral; handler, inter, "You need the Manage Server permission to change the bot's settings.");
+        return Ok(());
+    }
+
+    let cmd = AppealChannelCommand::from_interaction(data.into())?;
+    let channel_id = cmd.channel;
+
+    update_config_by(&handler, moderator_user, guild_id, |guild_config| guild_config["appeals_channel_id"] = toml_edit::value(channel_id.get() as i64)).await?;
+
+    response!(handler, inter, "Configuration successful.  Ban appeals will now be sent to <#{}>.", channel_id);
+    Ok(())
+}
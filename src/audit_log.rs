@@ -0,0 +1,117 @@
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use twilight_model::channel::message::embed::EmbedField;
+use twilight_model::guild::audit_log::{AuditLogChange, AuditLogEntry, AuditLogEventType};
+use twilight_model::id::Id;
+use twilight_model::id::marker::{GuildMarker, UserMarker};
+use twilight_util::builder::embed::EmbedBuilder;
+
+use anyhow::anyhow;
+
+use smb_log_format::{ModLogEntry, ModLogAction};
+use crate::business_logic::{bot_user, format_user, get_modlog_channel};
+use crate::disk_log::ModLogEntryExt;
+use crate::history::{self, CaseKind};
+use crate::sanctions;
+use crate::state::BotHandler;
+
+// moderators don't always go through the bot.  anything they do through the Discord client shows
+// up in the guild's audit log, and Discord sends us every new audit log entry over the gateway, so
+// we translate the ones we care about into modlog entries here.
+
+/// Called for every GUILD_AUDIT_LOG_ENTRY_CREATE.
+pub(crate) async fn on_audit_log_entry(handler: Arc<BotHandler>, entry: AuditLogEntry) {
+    if let Err(e) = ingest(&handler, &entry).await {
+        tracing::error!("Error recording audit log entry {}: {}", entry.id, e);
+    }
+}
+
+async fn ingest(handler: &BotHandler, entry: &AuditLogEntry) -> anyhow::Result<()> {
+    let (Some(guild_id), Some(moderator_id)) = (entry.guild_id, entry.user_id) else {
+        return Ok(());
+    };
+    let Some(target_id) = entry.target_id.map(|id| id.cast::<UserMarker>()) else {
+        return Ok(());
+    };
+    // this is the only place a sanction role being taken away shows up, including when the bot did
+    // it itself
+    if entry.action_type == AuditLogEventType::MemberRoleUpdate {
+        let removed = entry.changes.iter()
+            .filter_map(|change| match change {
+                AuditLogChange::RoleRemoved {new, ..} => Some(new.iter().map(|role| role.id.get())),
+                _ => None,
+            })
+            .flatten()
+            .collect::<Vec<_>>();
+        sanctions::on_roles_removed(&handler.state, guild_id, target_id, &removed);
+    }
+    // anything the bot did itself was already logged by whichever handler did it
+    if moderator_id == bot_user(handler)?.id {
+        return Ok(());
+    }
+    let reason = entry.reason.clone().unwrap_or_else(|| "No reason given.".to_owned());
+
+    let (title, kind, action, detail) = match entry.action_type {
+        AuditLogEventType::MemberBanAdd => ("Member banned", CaseKind::Ban, ModLogAction::Ban {user_id: target_id.get(), reason: reason.clone()}, reason),
+        AuditLogEventType::MemberBanRemove => ("Member unbanned", CaseKind::Unban, ModLogAction::Unban {user_id: target_id.get(), reason: reason.clone()}, reason),
+        AuditLogEventType::MemberKick => ("Member kicked", CaseKind::Kick, ModLogAction::Kick {user_id: target_id.get(), reason: reason.clone()}, reason),
+        AuditLogEventType::MemberUpdate => {
+            // the only member update we care about is a timeout being given or taken away
+            let Some(until) = entry.changes.iter().find_map(|change| match change {
+                AuditLogChange::CommunicationDisabledUntil {new, ..} => Some(*new),
+                _ => None,
+            }) else {
+                return Ok(());
+            };
+            let until = until.map(|t| UNIX_EPOCH + Duration::from_secs(t.as_secs().max(0) as u64));
+            let detail = match until {
+                Some(until) => format!("Until <t:{}:f>.  {}", until.duration_since(UNIX_EPOCH)?.as_secs(), reason),
+                None => format!("Timeout removed.  {}", reason),
+            };
+            let title = if until.is_some() {"Member timed out"} else {"Member timeout removed"};
+            (title, CaseKind::Timeout, ModLogAction::Timeout {user_id: target_id.get(), until, reason}, detail)
+        },
+        AuditLogEventType::MemberRoleUpdate => {
+            let mut added = Vec::new();
+            let mut removed = Vec::new();
+            for change in entry.changes.iter() {
+                match change {
+                    AuditLogChange::RoleAdded {new, ..} => added.extend(new.iter().map(|role| role.id.get())),
+                    AuditLogChange::RoleRemoved {new, ..} => removed.extend(new.iter().map(|role| role.id.get())),
+                    _ => {},
+                }
+            }
+            let mention = |ids: &[u64]| ids.iter().map(|id| format!("<@&{}>", id)).collect::<Vec<_>>().join(" ");
+            let detail = format!("Added: {}  Removed: {}", mention(&added), mention(&removed));
+            ("Member roles changed", CaseKind::Roles, ModLogAction::RoleChange {user_id: target_id.get(), added, removed}, detail)
+        },
+        AuditLogEventType::MessageDelete => {
+            let options = entry.options.as_ref().ok_or(anyhow!("Message delete entry without options"))?;
+            let channel_id = options.channel_id.map(Id::get).unwrap_or(0);
+            let count = options.count.unwrap_or(1);
+            let detail = format!("{} message(s) in <#{}>", count, channel_id);
+            ("Messages deleted by moderator", CaseKind::DeleteMessage, ModLogAction::ExternalMessageDelete {author_id: target_id.get(), channel_id, count}, detail)
+        },
+        _ => return Ok(()),
+    };
+
+    let moderator = handler.client.user(moderator_id).await?.model().await?;
+    ModLogEntry::new(&moderator, None, SystemTime::now(), action).log(&handler.state);
+    history::record(&handler.state, guild_id, target_id, Some(moderator_id), kind, detail.clone());
+    post_to_modlog(handler, guild_id, title, target_id, &format_user(&moderator), &detail).await
+}
+
+async fn post_to_modlog(handler: &BotHandler, guild_id: Id<GuildMarker>, title: &str, target_id: Id<UserMarker>, moderator: &str, detail: &str) -> anyhow::Result<()> {
+    let Some(modlog_channel_id) = get_modlog_channel(&handler.state, guild_id) else {
+        return Ok(());
+    };
+    let builder = EmbedBuilder::new()
+        .title(format!("{} (outside the bot)", title))
+        .description(detail)
+        .field(EmbedField {name: "Member".to_string(), value: format!("<@{}>", target_id), inline: false})
+        .field(EmbedField {name: "Moderator".to_string(), value: moderator.to_owned(), inline: false});
+    let _pending = handler.state.metrics.modlog_post();
+    handler.client.create_message(modlog_channel_id).embeds(&[builder.build()])?.await?;
+    Ok(())
+}
@@ -1,46 +1,51 @@
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
-use std::time::SystemTime;
+use std::time::{Duration, SystemTime};
 
 use twilight_interactions::command::CommandModel;
-use twilight_model::application::interaction::{application_command::CommandData, Interaction};
+use twilight_model::application::interaction::{application_command::CommandData, modal::ModalInteractionData, Interaction};
+use twilight_model::channel::message::component::{ActionRow, Component, TextInput};
+use twilight_model::http::interaction::{InteractionResponse, InteractionResponseType};
 use twilight_model::channel::message::MessageFlags;
-use twilight_model::channel::message::embed::EmbedField;
+use twilight_model::channel::message::embed::{Embed, EmbedField};
 use twilight_model::guild::{Permissions, PartialMember, Member};
 use twilight_model::id::Id;
-use twilight_model::id::marker::{GuildMarker, ChannelMarker, RoleMarker};
-use twilight_model::user::User;
+use twilight_model::id::marker::{GuildMarker, ChannelMarker, RoleMarker, UserMarker};
+use twilight_model::user::{CurrentUser, User};
 use twilight_util::builder::InteractionResponseDataBuilder;
 use twilight_util::builder::embed::EmbedBuilder;
-use twl_fw::InteractionHandler;
 use twl_fw::response;
 
 use anyhow::anyhow;
 
-use crate::commands::{ReasonCommand, ChannelCommand, AddModRoleCommand};
+use crate::commands::{ReasonCommand, ChannelCommand, ReportChannelCommand, AddModRoleCommand, DeleteModRoleCommand, AddModUserCommand, DeleteModUserCommand, PermissionsCommand, PermissionsAllowCommand, PermissionsDenyCommand, PermissionsResetCommand, NativePermissionsCommand};
 use smb_log_format::{ModLogEntry, ModLogAction, ModLogMessage};
 use crate::disk_log::{ModLogEntryExt, ModLogMessageExt};
-use crate::{get_config, save_config};
+use crate::{is_bot_owner, save_config};
+use crate::state::{BotHandler, BotState};
+use crate::history::{self, CaseKind};
+use crate::actions::{self, Action};
 
 use toml_edit::value;
 
-fn get_guild(inter: &Interaction, data: &CommandData) -> anyhow::Result<Id<GuildMarker>> {
+pub(crate) fn get_guild(inter: &Interaction, data: &CommandData) -> anyhow::Result<Id<GuildMarker>> {
     inter.guild_id
         .or(data.guild_id)
         .ok_or(anyhow!("Cannot figure out what guild this command is being run in."))
 }
 
-fn get_initiating_user(inter: &Interaction) -> anyhow::Result<&User> {
+pub(crate) fn get_initiating_user(inter: &Interaction) -> anyhow::Result<&User> {
     inter.user.as_ref()
         .or(inter.member.as_ref().and_then(|x: &PartialMember|x.user.as_ref()))
         .ok_or(anyhow!("Can't figure out who sent this interaction"))
 }
 
 
-pub(crate) async fn delete_message(handler: Arc<InteractionHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+pub(crate) async fn delete_message(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
     let guild_id = get_guild(&inter, &data)?;
     let moderator_user = get_initiating_user(&inter)?;
 
-    if !inter.member.as_ref().is_some_and(|member| is_user_a_moderator(&handler, member, guild_id)) {
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "Delete message")) {
         response!(ephemeral; handler, inter, "You do not have permission to use that command.");
         return Ok(());
     }
@@ -48,9 +53,19 @@ pub(crate) async fn delete_message(handler: Arc<InteractionHandler>, inter: Inte
     // incantation to get the message object we were invoked with
     let offending_message = data.resolved.unwrap().messages.remove(&Id::new(data.target_id.unwrap().get())).unwrap();
 
-    ModLogEntry::new(&moderator_user, inter.channel.as_ref().map(|x|x.id), SystemTime::now(), ModLogAction::DeleteMessage(ModLogMessage::from_message(&offending_message).await)).log();
+    // save the message (and download its attachments) before it's gone
+    let logged_message = ModLogMessage::from_message(&handler.state, &offending_message).await;
 
-    if let Some(modlog_channel_id) = get_modlog_channel(guild_id) {
+    let action = Action::DeleteMessage {channel_id: offending_message.channel_id, message_id: offending_message.id};
+    if !actions::execute(&handler, guild_id, None, &Actor::User(moderator_user), action).await? {
+        response!(ephemeral; handler, inter, "Shadow mode is on, so the message was not deleted.  What would have happened has been posted to the modlog channel.");
+        return Ok(());
+    }
+
+    ModLogEntry::new(&moderator_user, inter.channel.as_ref().map(|x|x.id), SystemTime::now(), ModLogAction::DeleteMessage(logged_message)).log(&handler.state);
+    history::record(&handler.state, guild_id, offending_message.author.id, Some(moderator_user.id), CaseKind::DeleteMessage, offending_message.content.clone());
+
+    if let Some(modlog_channel_id) = get_modlog_channel(&handler.state, guild_id) {
         let mut builder = EmbedBuilder::new()
                     .title(format!("Message removed by moderator"))
                     .description(offending_message.content)
@@ -63,27 +78,72 @@ pub(crate) async fn delete_message(handler: Arc<InteractionHandler>, inter: Inte
         for attachment in offending_message.attachments {
             builder = builder.field(EmbedField {name: format!("Attachment: {}", attachment.filename), value: attachment.proxy_url, inline: false});
         }
+        let _pending = handler.state.metrics.modlog_post();
         handler.client.create_message(modlog_channel_id).embeds(&[builder.build()])?.await?;
     } else {
         response!(handler, inter, "The modlog channel in this server has not been set up yet.  Moderation action will be logged to the logfile only.");
     };
 
-    handler.client.delete_message(offending_message.channel_id, offending_message.id).await?;
-
     response!(ephemeral; handler, inter, "Message deleted");
     Ok(())
 }
 
-fn format_user(user: &twilight_model::user::User) -> String {
+/// Whoever is responsible for a moderation action: a moderator who ran a command, or the bot acting
+/// on its own (say, when a timed lock runs out).
+pub(crate) enum Actor<'a> {
+    User(&'a User),
+    Bot(CurrentUser),
+}
+
+impl Actor<'_> {
+    pub(crate) fn log_entry(&self, channel_id: Option<Id<ChannelMarker>>, action: ModLogAction) -> ModLogEntry {
+        match self {
+            Actor::User(user) => ModLogEntry::new(user, channel_id, SystemTime::now(), action),
+            Actor::Bot(bot) => ModLogEntry::new_by_bot(bot, channel_id, SystemTime::now(), action),
+        }
+    }
+
+    /// The moderator's user ID, or None if the bot did it by itself.
+    pub(crate) fn user_id(&self) -> Option<Id<UserMarker>> {
+        match self {
+            Actor::User(user) => Some(user.id),
+            Actor::Bot(_) => None,
+        }
+    }
+
+    pub(crate) fn describe(&self) -> String {
+        match self {
+            Actor::User(user) => format_user(user),
+            Actor::Bot(bot) => format!("{} (automatic)", bot.name),
+        }
+    }
+}
+
+/// Post an embed to the guild's modlog channel, if it has one.  Returns whether there was one.
+pub(crate) async fn post_to_modlog(handler: &BotHandler, guild_id: Id<GuildMarker>, embed: Embed) -> anyhow::Result<bool> {
+    let Some(modlog_channel_id) = get_modlog_channel(&handler.state, guild_id) else {
+        return Ok(false);
+    };
+    let _pending = handler.state.metrics.modlog_post();
+    handler.client.create_message(modlog_channel_id).embeds(&[embed])?.await?;
+    Ok(true)
+}
+
+/// The guild's name if we have it cached, otherwise its ID.
+pub(crate) fn guild_name(handler: &BotHandler, guild_id: Id<GuildMarker>) -> String {
+    handler.cache().guild(guild_id).map(|guild| guild.name().to_owned()).unwrap_or_else(|| guild_id.to_string())
+}
+
+pub(crate) fn format_user(user: &twilight_model::user::User) -> String {
     match user.discriminator {
         0 => format!("@{} (<@{}>)", user.name, user.id),
         disc => format!("{}#{:04} (<@{}>)", user.name, disc, user.id),
     }
 }
 
-pub(crate) async fn purge_hour(handler: Arc<InteractionHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+pub(crate) async fn purge_hour(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
     let guild_id = get_guild(&inter, &data)?;
-    if !inter.member.as_ref().is_some_and(|member| is_user_a_moderator(&handler, member, guild_id)) {
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "Purge last hour")) {
         response!(ephemeral; handler, inter, "You do not have permission to use that command.");
         return Ok(());
     }
@@ -91,11 +151,11 @@ pub(crate) async fn purge_hour(handler: Arc<InteractionHandler>, inter: Interact
     Ok(())
 }
 
-pub(crate) async fn purge_to_here(handler: Arc<InteractionHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+pub(crate) async fn purge_to_here(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
     let guild_id = get_guild(&inter, &data)?;
     let moderator_user = get_initiating_user(&inter)?;
 
-    if !inter.member.as_ref().is_some_and(|member| is_user_a_moderator(&handler, member, guild_id)) {
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "Purge last hour")) {
         response!(ephemeral; handler, inter, "You do not have permission to use that command.");
         return Ok(());
     }
@@ -107,11 +167,11 @@ pub(crate) async fn purge_to_here(handler: Arc<InteractionHandler>, inter: Inter
     Ok(())
 }
 
-pub(crate) async fn reason(handler: Arc<InteractionHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+pub(crate) async fn reason(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
     let guild_id = get_guild(&inter, &data)?;
     let moderator_user = get_initiating_user(&inter)?;
 
-    if !inter.member.as_ref().is_some_and(|member| is_user_a_moderator(&handler, member, guild_id)) {
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "reason")) {
         response!(ephemeral; handler, inter, "You do not have permission to use that command.");
         return Ok(());
     }
@@ -121,7 +181,7 @@ pub(crate) async fn reason(handler: Arc<InteractionHandler>, inter: Interaction,
     let cmd = ReasonCommand::from_interaction(data.into())?;
 
 
-    if let Some(modlog_channel_id) = get_modlog_channel(guild_id) {
+    if let Some(modlog_channel_id) = get_modlog_channel(&handler.state, guild_id) {
         let mut builder = EmbedBuilder::new()
                         .title(format!("Reason added by moderator"))
                         .field(EmbedField {name: "Moderator".to_string(), value: format_user(&moderator_user), inline: false})
@@ -130,19 +190,26 @@ pub(crate) async fn reason(handler: Arc<InteractionHandler>, inter: Interaction,
             // this *should* always be present but i'm not taking ANY chances
             builder = builder.field(EmbedField {name: "Channel".to_string(), value: format!("<#{}>", channel.id), inline: false});
         }
+        let _pending = handler.state.metrics.modlog_post();
         handler.client.create_message(modlog_channel_id).embeds(&[builder.build()])?.await?;
         response!(ephemeral; handler, inter, "Reason recorded in the modlog.");
     } else {
         response!(ephemeral; handler, inter, "The modlog channel in this server has not been set up yet.  Moderation action will be logged to the logfile only.");
     };
     
-    ModLogEntry::new(&moderator_user, inter.channel.as_ref().map(|x|x.id), timestamp, ModLogAction::Reason(cmd.reason)).log();
+    ModLogEntry::new(&moderator_user, inter.channel.as_ref().map(|x|x.id), timestamp, ModLogAction::Reason(cmd.reason)).log(&handler.state);
 
     Ok(())
 }
 
-pub(crate) async fn channel(handler: Arc<InteractionHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+pub(crate) async fn channel(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
     let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+
+    if !is_server_admin(&handler, &inter) {
+        response!(ephemeral; handler, inter, "You need the Manage Server permission to change the bot's settings.");
+        return Ok(());
+    }
 
     let cmd = ChannelCommand::from_interaction(data.into())?;
 
@@ -162,20 +229,44 @@ pub(crate) async fn channel(handler: Arc<InteractionHandler>, inter: Interaction
     }
     */
     
-    update_config(guild_id, |guild_config| guild_config["modlog_channel_id"] = toml_edit::value(channel_id.get() as i64));
+    update_config_by(&handler, moderator_user, guild_id, |guild_config| guild_config["modlog_channel_id"] = toml_edit::value(channel_id.get() as i64)).await?;
     
     response!(handler, inter, "Configuration successful.  <#{}> is now the modlog channel.", channel_id);
     Ok(())
 }
 
-pub(crate) async fn add_modrole(handler: Arc<InteractionHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+pub(crate) async fn report_channel(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+
+    if !is_server_admin(&handler, &inter) {
+        response!(ephemeral; handler, inter, "You need the Manage Server permission to change the bot's settings.");
+        return Ok(());
+    }
+
+    let cmd = ReportChannelCommand::from_interaction(data.into())?;
+    let channel_id = cmd.channel;
+
+    update_config_by(&handler, moderator_user, guild_id, |guild_config| guild_config["reports_channel_id"] = toml_edit::value(channel_id.get() as i64)).await?;
+
+    response!(handler, inter, "Configuration successful.  Reports from members will now be sent to <#{}>.", channel_id);
+    Ok(())
+}
+
+pub(crate) async fn add_modrole(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
     let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+    if !is_server_admin(&handler, &inter) {
+        response!(ephemeral; handler, inter, "You need the Manage Server permission to change the bot's settings.");
+        return Ok(());
+    }
+
     let cmd = AddModRoleCommand::from_interaction(data.into())?;
     let role_id = cmd.role.get();
     
     let mut message = String::new();
 
-    update_config(guild_id, |guild_config| {
+    update_config_by(&handler, moderator_user, guild_id, |guild_config| {
         let Some(mod_roles) = guild_config.as_table_mut().and_then(|table| table.entry("moderator_roles").or_insert(toml_edit::value(toml_edit::Array::new())).as_array_mut()) else {
             message = "Config file is not in a valid format.  No changes were made.".into();
             return;
@@ -187,22 +278,28 @@ pub(crate) async fn add_modrole(handler: Arc<InteractionHandler>, inter: Interac
             message = format!("Successfully made <@&{}> a moderator role.\n", role_id);
         }
         let current_role_ids = mod_roles.iter().filter_map(|x| x.as_integer()).collect::<Vec<i64>>();
-        message.push_str(&format_list_of_roles(&current_role_ids));
-    });
+        message.push_str(&format_list_of_roles(&current_role_ids, &config_ids(guild_config, "moderator_users")));
+    }).await?;
 
     response!(ephemeral; handler, inter, "{}", message);
 
     Ok(())
 }
 
-pub(crate) async fn del_modrole(handler: Arc<InteractionHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+pub(crate) async fn del_modrole(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
     let guild_id = get_guild(&inter, &data)?;
-    let cmd = AddModRoleCommand::from_interaction(data.into())?;
+    let moderator_user = get_initiating_user(&inter)?;
+    if !is_server_admin(&handler, &inter) {
+        response!(ephemeral; handler, inter, "You need the Manage Server permission to change the bot's settings.");
+        return Ok(());
+    }
+
+    let cmd = DeleteModRoleCommand::from_interaction(data.into())?;
     let role_id = cmd.role.get();
     
     let mut message = String::new();
 
-    update_config(guild_id, |guild_config| {
+    update_config_by(&handler, moderator_user, guild_id, |guild_config| {
         let Some(mod_roles) = guild_config.as_table_mut().and_then(|table| table.entry("moderator_roles").or_insert(toml_edit::value(toml_edit::Array::new())).as_array_mut()) else {
             message = "Config file is not in a valid format.  No changes were made.".into();
             return;
@@ -215,8 +312,74 @@ pub(crate) async fn del_modrole(handler: Arc<InteractionHandler>, inter: Interac
             message = "That role is already not a moderator role.\n".into();
         }
         let current_role_ids = mod_roles.iter().filter_map(|x| x.as_integer()).collect::<Vec<i64>>();
-        message.push_str(&format_list_of_roles(&current_role_ids));
-    });
+        message.push_str(&format_list_of_roles(&current_role_ids, &config_ids(guild_config, "moderator_users")));
+    }).await?;
+    response!(ephemeral; handler, inter, "{}", message);
+
+    Ok(())
+}
+
+pub(crate) async fn add_moduser(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+    if !is_server_admin(&handler, &inter) {
+        response!(ephemeral; handler, inter, "You need the Manage Server permission to change the bot's settings.");
+        return Ok(());
+    }
+
+    let cmd = AddModUserCommand::from_interaction(data.into())?;
+    let user_id = cmd.user.get();
+
+    let mut message = String::new();
+
+    update_config_by(&handler, moderator_user, guild_id, |guild_config| {
+        let Some(mod_users) = guild_config.as_table_mut().and_then(|table| table.entry("moderator_users").or_insert(toml_edit::value(toml_edit::Array::new())).as_array_mut()) else {
+            message = "Config file is not in a valid format.  No changes were made.".into();
+            return;
+        };
+        if mod_users.iter().any(|s| s.as_integer().is_some_and(|val| val==user_id as i64)) {
+            message = "That user is already a moderator.\n".into();
+        } else {
+            mod_users.push(user_id as i64);
+            message = format!("Successfully made <@{}> a moderator.\n", user_id);
+        }
+        let current_user_ids = mod_users.iter().filter_map(|x| x.as_integer()).collect::<Vec<i64>>();
+        message.push_str(&format_list_of_roles(&config_ids(guild_config, "moderator_roles"), &current_user_ids));
+    }).await?;
+
+    response!(ephemeral; handler, inter, "{}", message);
+
+    Ok(())
+}
+
+pub(crate) async fn del_moduser(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+    if !is_server_admin(&handler, &inter) {
+        response!(ephemeral; handler, inter, "You need the Manage Server permission to change the bot's settings.");
+        return Ok(());
+    }
+
+    let cmd = DeleteModUserCommand::from_interaction(data.into())?;
+    let user_id = cmd.user.get();
+
+    let mut message = String::new();
+
+    update_config_by(&handler, moderator_user, guild_id, |guild_config| {
+        let Some(mod_users) = guild_config.as_table_mut().and_then(|table| table.entry("moderator_users").or_insert(toml_edit::value(toml_edit::Array::new())).as_array_mut()) else {
+            message = "Config file is not in a valid format.  No changes were made.".into();
+            return;
+        };
+        let pos = mod_users.iter().position(|s| s.as_integer().is_some_and(|val| val==user_id as i64));
+        if let Some(idx) = pos {
+            mod_users.remove(idx);
+            message = format!("Successfully revoked moderator status from <@{}>.\n", user_id);
+        } else {
+            message = "That user is already not a moderator.\n".into();
+        }
+        let current_user_ids = mod_users.iter().filter_map(|x| x.as_integer()).collect::<Vec<i64>>();
+        message.push_str(&format_list_of_roles(&config_ids(guild_config, "moderator_roles"), &current_user_ids));
+    }).await?;
     response!(ephemeral; handler, inter, "{}", message);
 
     Ok(())
@@ -226,8 +389,8 @@ pub(crate) async fn del_modrole(handler: Arc<InteractionHandler>, inter: Interac
 // (and thus does not allow us to use it with tokio) 
 // any non-Send value (the mutex guard) is assigned to a variable, regardless of whether it is held across an await
 // point
-fn update_config<T>(guild_id: Id<GuildMarker>, action: impl FnOnce(&mut toml_edit::Item) -> T) -> T {
-    let mut config = get_config().lock().unwrap();
+pub(crate) fn update_config<T>(state: &BotState, guild_id: Id<GuildMarker>, action: impl FnOnce(&mut toml_edit::Item) -> T) -> T {
+    let mut config = state.config.lock().unwrap();
     let guild_config = &mut config[guild_id.to_string().as_str()];
     //guild_config["role_id"] = value(0);
     // fun fact!  this cast will cause an overflow eventually and cause the IDs stored in the
@@ -239,36 +402,386 @@ fn update_config<T>(guild_id: Id<GuildMarker>, action: impl FnOnce(&mut toml_edi
         *guild_config = toml_edit::Item::Table(t.clone().into_table());
     }
     std::mem::drop(config); // release our held mutex so that save_config() can acquire it again
-    save_config();
+    save_config(state);
     res
 }
 
+/// Every top-level key in a guild's config, with its value as written in config.toml.
+fn snapshot_config(guild_config: &toml_edit::Item) -> BTreeMap<String, String> {
+    guild_config.as_table_like()
+        .map(|table| table.iter().map(|(key, item)| (key.to_owned(), item.to_string().trim().to_owned())).collect())
+        .unwrap_or_default()
+}
+
+/// update_config(), for changes made by a person.  Every key that comes out different is logged as
+/// a ConfigChange made by `user` and posted to the modlog channel, so there's a record of who
+/// changed what.
+pub(crate) async fn update_config_by<T>(handler: &BotHandler, user: &User, guild_id: Id<GuildMarker>, action: impl FnOnce(&mut toml_edit::Item) -> T) -> anyhow::Result<T> {
+    let (res, changes) = update_config(&handler.state, guild_id, |guild_config| {
+        let before = snapshot_config(guild_config);
+        let res = action(guild_config);
+        let after = snapshot_config(guild_config);
+        let changes = before.keys().chain(after.keys()).collect::<BTreeSet<_>>().into_iter()
+            .filter(|key| before.get(*key) != after.get(*key))
+            .map(|key| (key.clone(), before.get(key).cloned(), after.get(key).cloned()))
+            .collect::<Vec<_>>();
+        (res, changes)
+    });
+    if changes.is_empty() {
+        return Ok(res);
+    }
+
+    let mut builder = EmbedBuilder::new()
+        .title("Configuration changed")
+        .field(EmbedField {name: "Changed by".to_string(), value: format_user(user), inline: false});
+    for (key, old_value, new_value) in changes {
+        let describe = |value: &Option<String>| value.as_ref().map_or_else(|| "(unset)".to_owned(), |value| format!("`{}`", value));
+        let mut summary = format!("{} → {}", describe(&old_value), describe(&new_value));
+        if summary.chars().count() > 1024 {
+            summary = summary.chars().take(1021).collect::<String>() + "...";
+        }
+        builder = builder.field(EmbedField {name: key.clone(), value: summary, inline: false});
+        Actor::User(user).log_entry(None, ModLogAction::ConfigChange {
+            guild_id: guild_id.get(),
+            key,
+            old_value,
+            new_value,
+        }).log(&handler.state);
+    }
+    post_to_modlog(handler, guild_id, builder.build()).await?;
+    Ok(res)
+}
+
 // ditto
-fn get_modlog_channel(guild_id: Id<GuildMarker>) -> Option<Id<ChannelMarker>> {
-    let config = get_config().lock().unwrap();
+pub(crate) fn get_modlog_channel(state: &BotState, guild_id: Id<GuildMarker>) -> Option<Id<ChannelMarker>> {
+    get_config_channel(state, guild_id, "modlog_channel_id")
+}
+
+pub(crate) fn get_config_channel(state: &BotState, guild_id: Id<GuildMarker>, key: &str) -> Option<Id<ChannelMarker>> {
+    let config = state.config.lock().unwrap();
 
     let guild_config = config.get(guild_id.get().to_string().as_str())?;
-    let channel_id = guild_config.get("modlog_channel_id")?.as_integer()? as u64;
+    let channel_id = guild_config.get(key)?.as_integer()? as u64;
     Some(Id::new(channel_id))
 }
 
-fn format_list_of_roles(role_ids: &[i64]) -> String {
-    if role_ids.is_empty() {
-        return "No moderator roles are currently set.  No one will be able to use the moderation commands.".to_string();
-    } else if role_ids.len() == 1 {
-        format!("<@&{}> is currently the only moderator role.", role_ids[0] as u64)
+pub(crate) fn get_config_role(state: &BotState, guild_id: Id<GuildMarker>, key: &str) -> Option<Id<RoleMarker>> {
+    get_config_integer(state, guild_id, key).map(|role_id| Id::new(role_id as u64))
+}
+
+pub(crate) fn get_config_integer(state: &BotState, guild_id: Id<GuildMarker>, key: &str) -> Option<i64> {
+    let config = state.config.lock().unwrap();
+    config.get(guild_id.get().to_string().as_str())?.get(key)?.as_integer()
+}
+
+/// Reply to an interaction by popping up a modal with one text box per entry in `inputs`.
+pub(crate) async fn respond_with_modal(handler: &BotHandler, inter: &Interaction, custom_id: String, title: String, inputs: Vec<TextInput>) -> anyhow::Result<()> {
+    let components = inputs.into_iter()
+        .map(|input| Component::ActionRow(ActionRow {components: vec![Component::TextInput(input)]}))
+        .collect::<Vec<_>>();
+    let response = InteractionResponse {
+        kind: InteractionResponseType::Modal,
+        data: Some(InteractionResponseDataBuilder::new().custom_id(custom_id).title(title).components(components).build()),
+    };
+    handler.client.interaction(inter.application_id).create_response(inter.id, &inter.token, &response).await?;
+    Ok(())
+}
+
+/// Parse a duration like "90s", "30m", "2h" or "1d".  A bare number is taken as minutes.
+pub(crate) fn parse_duration(s: &str) -> Option<Duration> {
+    let s = s.trim();
+    let (number, unit) = s.find(|c: char| !c.is_ascii_digit()).map_or((s, "m"), |idx| s.split_at(idx));
+    let number: u64 = number.parse().ok()?;
+    let multiplier = match unit.trim() {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    Some(Duration::from_secs(number.checked_mul(multiplier)?))
+}
+
+/// Reply to an interaction with an embed only the person who triggered it can see.
+pub(crate) async fn respond_with_embed(handler: &BotHandler, inter: &Interaction, embed: Embed) -> anyhow::Result<()> {
+    let response = InteractionResponse {
+        kind: InteractionResponseType::ChannelMessageWithSource,
+        data: Some(InteractionResponseDataBuilder::new().embeds([embed]).flags(MessageFlags::EPHEMERAL).build()),
+    };
+    handler.client.interaction(inter.application_id).create_response(inter.id, &inter.token, &response).await?;
+    Ok(())
+}
+
+/// Reply to a button press by taking the buttons off the message they were attached to and noting
+/// what was done about it in its first embed.
+pub(crate) async fn resolve_buttons(handler: &BotHandler, inter: &Interaction, outcome: &str) -> anyhow::Result<()> {
+    let mut embeds = inter.message.as_ref().map(|message| message.embeds.clone()).unwrap_or_default();
+    if let Some(embed) = embeds.first_mut() {
+        embed.fields.push(EmbedField {name: "Outcome".to_string(), value: outcome.to_owned(), inline: false});
+    }
+    let response = InteractionResponse {
+        kind: InteractionResponseType::UpdateMessage,
+        data: Some(InteractionResponseDataBuilder::new().embeds(embeds).components([]).build()),
+    };
+    handler.client.interaction(inter.application_id).create_response(inter.id, &inter.token, &response).await?;
+    Ok(())
+}
+
+/// The bot's own user, for modlog entries recording things the bot did on its own initiative.
+pub(crate) fn bot_user(handler: &BotHandler) -> anyhow::Result<CurrentUser> {
+    handler.cache().current_user().ok_or(anyhow!("Didn't get current_user from READY event..."))
+}
+
+/// Fish the value of the text box with the given custom ID out of a submitted modal.
+pub(crate) fn modal_value<'a>(data: &'a ModalInteractionData, custom_id: &str) -> Option<&'a str> {
+    data.components.iter()
+        .flat_map(|row| row.components.iter())
+        .find(|component| component.custom_id == custom_id)
+        .and_then(|component| component.value.as_deref())
+}
+
+/// "a", "a and b", "a, b and c"
+fn join_mentions(mentions: &[String]) -> String {
+    match mentions {
+        [] => String::new(),
+        [only] => only.clone(),
+        [rest @ .., last] => format!("{} and {}", rest.join(", "), last),
+    }
+}
+
+fn format_list_of_roles(role_ids: &[i64], user_ids: &[i64]) -> String {
+    if role_ids.is_empty() && user_ids.is_empty() {
+        return "No moderator roles or users are currently set.  No one will be able to use the moderation commands.".to_string();
+    }
+    let roles = role_ids.iter().map(|id| format!("<@&{}>", *id as u64)).collect::<Vec<_>>();
+    let users = user_ids.iter().map(|id| format!("<@{}>", *id as u64)).collect::<Vec<_>>();
+    let mut message = match roles.len() {
+        0 => "There are no moderator roles.".to_string(),
+        1 => format!("{} is currently the only moderator role.", roles[0]),
+        _ => format!("Current moderator roles are {}.", join_mentions(&roles)),
+    };
+    match users.len() {
+        0 => {},
+        1 => message.push_str(&format!("\n{} is also a moderator without a role.", users[0])),
+        _ => message.push_str(&format!("\n{} are also moderators without a role.", join_mentions(&users))),
+    }
+    message
+}
+
+/// The integers in an array in the guild's config, e.g. a list of role IDs.
+fn config_ids(guild_config: &toml_edit::Item, key: &str) -> Vec<i64> {
+    guild_config.get(key)
+        .and_then(|ids| ids.as_array())
+        .map(|ids| ids.iter().filter_map(|id| id.as_integer()).collect())
+        .unwrap_or_default()
+}
+
+/// The guild's moderator roles, for when we need to know who's staff without an interaction to
+/// go on.
+pub(crate) fn moderator_roles(state: &BotState, guild_id: Id<GuildMarker>) -> Vec<u64> {
+    let config = state.config.lock().unwrap();
+    config.get(guild_id.get().to_string().as_str())
+        .map(|guild_config| config_ids(guild_config, "moderator_roles").into_iter().map(|role| role as u64).collect())
+        .unwrap_or_default()
+}
+
+/// Ditto for the members who are moderators on their own, without a role.
+pub(crate) fn moderator_users(state: &BotState, guild_id: Id<GuildMarker>) -> Vec<u64> {
+    let config = state.config.lock().unwrap();
+    config.get(guild_id.get().to_string().as_str())
+        .map(|guild_config| config_ids(guild_config, "moderator_users").into_iter().map(|user| user as u64).collect())
+        .unwrap_or_default()
+}
+
+/// Everything whose access can be set per guild with /permissions.  These are command names, plus
+/// "warn", "timeout", "ban" and "appeals" for the buttons that do those things.  Each comes with
+/// the Discord permission that grants it when a guild has native permissions turned on.
+pub(crate) const PERMISSIONS: &[(&str, Permissions)] = &[
+    ("Delete message", Permissions::MANAGE_MESSAGES),
+    ("Purge last hour", Permissions::MANAGE_MESSAGES),
+    ("User info", Permissions::MODERATE_MEMBERS),
+    ("reason", Permissions::MODERATE_MEMBERS),
+    ("note", Permissions::MODERATE_MEMBERS),
+    ("history", Permissions::MODERATE_MEMBERS),
+    ("modmail", Permissions::MODERATE_MEMBERS),
+    ("lock", Permissions::MANAGE_CHANNELS),
+    ("unlock", Permissions::MANAGE_CHANNELS),
+    ("slowmode", Permissions::MANAGE_CHANNELS),
+    ("lockdown", Permissions::MANAGE_GUILD),
+    ("quarantine", Permissions::MANAGE_ROLES),
+    ("release", Permissions::MANAGE_ROLES),
+    ("warn", Permissions::MODERATE_MEMBERS),
+    ("timeout", Permissions::MODERATE_MEMBERS),
+    ("ban", Permissions::BAN_MEMBERS),
+    ("appeals", Permissions::BAN_MEMBERS),
+];
+
+/// Whether members with the matching Discord permission count as moderators.  Unless a guild has
+/// said otherwise, this is on until it has at least one moderator role or user, so a freshly
+/// invited bot is usable.
+fn native_permissions_enabled(guild_config: Option<&toml_edit::Item>) -> bool {
+    // deleting from a list that was never set up leaves an empty one behind, which doesn't count
+    guild_config.and_then(|guild_config| guild_config.get("native_permissions"))
+        .and_then(|enabled| enabled.as_bool())
+        .unwrap_or_else(|| guild_config.map_or(true, |guild_config| config_ids(guild_config, "moderator_roles").is_empty() && config_ids(guild_config, "moderator_users").is_empty()))
+}
+
+/// Whether whoever sent an interaction may change the bot's settings for the server: anyone with
+/// Administrator or Manage Server, or the bot's owner.
+pub(crate) fn is_server_admin(handler: &BotHandler, inter: &Interaction) -> bool {
+    if get_initiating_user(inter).is_ok_and(|user| is_bot_owner(&handler.state, user.id)) {
+        return true;
+    }
+    inter.member.as_ref()
+        .and_then(|member| member.permissions)
+        .is_some_and(|permissions| permissions.intersects(Permissions::ADMINISTRATOR | Permissions::MANAGE_GUILD))
+}
+
+/// Whether a member may use `command`.  A command listed in the guild's `permissions` table is
+/// open to the roles listed there, plus the moderator users, since the table only holds roles.
+/// Anything else is open to moderators.  With native permissions on, the matching Discord
+/// permission is enough either way.
+pub(crate) fn is_allowed_to_use(handler: &BotHandler, member: &PartialMember, guild_id: Id<GuildMarker>, command: &str) -> bool {
+    let (allowed_roles, moderator_users, native) = {
+        let config = handler.state.config.lock().unwrap();
+        let guild_config = config.get(guild_id.get().to_string().as_str());
+        let allowed_roles = guild_config
+            .and_then(|guild_config| guild_config.get("permissions"))
+            .and_then(|permissions| permissions.get(command))
+            .and_then(|roles| roles.as_array())
+            .map(|roles| roles.iter().filter_map(|role| role.as_integer()).map(|role| role as u64).collect::<Vec<_>>());
+        let moderator_users = guild_config.map(|guild_config| config_ids(guild_config, "moderator_users")).unwrap_or_default();
+        (allowed_roles, moderator_users, native_permissions_enabled(guild_config))
+    };
+
+    if native {
+        let required = PERMISSIONS.iter().find(|(name, _)| *name == command).map(|(_, permission)| *permission);
+        // member.permissions is computed by Discord for the channel the interaction came from
+        if let (Some(required), Some(permissions)) = (required, member.permissions) {
+            if permissions.contains(Permissions::ADMINISTRATOR) || permissions.contains(required) {
+                return true;
+            }
+        }
+    }
+    match allowed_roles {
+        Some(roles) => member.roles.iter().any(|role| roles.contains(&role.get()))
+            || member.user.as_ref().is_some_and(|user| moderator_users.contains(&(user.id.get() as i64))),
+        None => is_user_a_moderator(handler, member, guild_id),
+    }
+}
+
+pub(crate) async fn native_permissions(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+    if !is_server_admin(&handler, &inter) {
+        response!(ephemeral; handler, inter, "You need the Manage Server permission to change the bot's settings.");
+        return Ok(());
+    }
+
+    let cmd = NativePermissionsCommand::from_interaction(data.into())?;
+
+    update_config_by(&handler, moderator_user, guild_id, |guild_config| guild_config["native_permissions"] = value(cmd.enabled)).await?;
+
+    if cmd.enabled {
+        response!(ephemeral; handler, inter, "Members with the matching Discord permission can now use moderation commands, on top of any moderator roles.  That's Manage Messages for deleting messages, Ban Members for bans, Manage Channels for locks, and so on.");
     } else {
-        let s = role_ids[..role_ids.len()-1].iter().map(|id| format!("<@&{}>", *id as u64)).collect::<Vec<_>>().join(", ");
-        format!("Current moderator roles are {} and <@&{}>", s, role_ids[role_ids.len()-1] as u64)
+        response!(ephemeral; handler, inter, "Only moderator roles and roles given with /permissions can use moderation commands now.");
     }
+    Ok(())
 }
 
-fn is_user_a_moderator(handler: &InteractionHandler, member: &PartialMember, guild_id: Id<GuildMarker>) -> bool {
-    let config = get_config().lock().unwrap();
-    let Some(moderator_roles) = config.get(guild_id.get().to_string().as_str()).and_then(|guild_config| guild_config.get("moderator_roles")).and_then(|moderator_roles| moderator_roles.as_array()) else {
-        let guild_name = handler.cache().guild(guild_id).map(|guild| guild.name().to_owned()).unwrap_or_else(|| guild_id.to_string());
-        tracing::warn!("No moderator roles have been configured in server \"{}\".  Preventing anyone from using moderation commands", guild_name);
-        return false;
+fn format_permission(command: &str, roles: Option<&toml_edit::Array>) -> String {
+    match roles {
+        None => format!("`{}`: moderators", command),
+        Some(roles) if roles.is_empty() => format!("`{}`: nobody", command),
+        Some(roles) => format!("`{}`: {}", command, roles.iter().filter_map(|role| role.as_integer()).map(|role| format!("<@&{}>", role as u64)).collect::<Vec<_>>().join(", ")),
+    }
+}
+
+pub(crate) async fn permissions(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+    if !is_server_admin(&handler, &inter) {
+        response!(ephemeral; handler, inter, "You need the Manage Server permission to change the bot's settings.");
+        return Ok(());
+    }
+
+    let cmd = PermissionsCommand::from_interaction(data.into())?;
+
+    let command = match &cmd {
+        PermissionsCommand::Allow(PermissionsAllowCommand {command, ..}) | PermissionsCommand::Deny(PermissionsDenyCommand {command, ..}) | PermissionsCommand::Reset(PermissionsResetCommand {command}) => Some(command.as_str()),
+        PermissionsCommand::Show(_) => None,
     };
-    return moderator_roles.iter().filter_map(|entry| entry.as_integer()).any(|mod_role_id| member.roles.iter().any(|role| role.get() == mod_role_id as u64));
+    if let Some(command) = command {
+        if !PERMISSIONS.iter().any(|(name, _)| *name == command) {
+            response!(ephemeral; handler, inter, "There is no command called `{}`.  Commands you can set permissions for are: {}", command, PERMISSIONS.iter().map(|(name, _)| format!("`{}`", name)).collect::<Vec<_>>().join(", "));
+            return Ok(());
+        }
+    }
+
+    let allow = matches!(cmd, PermissionsCommand::Allow(_));
+    let message = update_config_by(&handler, moderator_user, guild_id, |guild_config| {
+        match cmd {
+            PermissionsCommand::Allow(PermissionsAllowCommand {command, role}) | PermissionsCommand::Deny(PermissionsDenyCommand {command, role}) => {
+                // the first time a command gets its own list, start it off with the moderator roles
+                // so that allowing one extra role doesn't lock out everyone else
+                let moderator_roles = guild_config.get("moderator_roles").and_then(|roles| roles.as_array()).cloned().unwrap_or_default();
+                let has_moderator_users = !config_ids(guild_config, "moderator_users").is_empty();
+                let permissions = &mut guild_config["permissions"];
+                if permissions.get(command.as_str()).is_none() {
+                    permissions[command.as_str()] = value(moderator_roles);
+                }
+                let Some(roles) = permissions[command.as_str()].as_array_mut() else {
+                    return "Config file is not in a valid format.  No changes were made.".to_owned();
+                };
+                let pos = roles.iter().position(|r| r.as_integer().is_some_and(|val| val==role.get() as i64));
+                match (allow, pos) {
+                    (true, None) => roles.push(role.get() as i64),
+                    (false, Some(idx)) => {roles.remove(idx);},
+                    _ => {},
+                }
+                let mut message = format_permission(&command, Some(roles));
+                if has_moderator_users {
+                    message.push_str("\nModerator users added with /add_moderator_user can still use it.");
+                }
+                message
+            },
+            PermissionsCommand::Reset(PermissionsResetCommand {command}) => {
+                if let Some(table) = guild_config.get_mut("permissions").and_then(|permissions| permissions.as_table_like_mut()) {
+                    table.remove(&command);
+                }
+                format_permission(&command, None)
+            },
+            PermissionsCommand::Show(_) => {
+                let lines = PERMISSIONS.iter()
+                    .filter_map(|(command, _)| guild_config.get("permissions").and_then(|permissions| permissions.get(*command)).and_then(|roles| roles.as_array()).map(|roles| format_permission(command, Some(roles))))
+                    .collect::<Vec<_>>();
+                if lines.is_empty() {
+                    "Every moderation command is open to moderators.".to_owned()
+                } else {
+                    format!("{}\nEverything else is open to moderators.", lines.join("\n"))
+                }
+            },
+        }
+    }).await?;
+
+    response!(ephemeral; handler, inter, "{}", message);
+    Ok(())
+}
+
+pub(crate) fn is_user_a_moderator(handler: &BotHandler, member: &PartialMember, guild_id: Id<GuildMarker>) -> bool {
+    let config = handler.state.config.lock().unwrap();
+    let guild_config = config.get(guild_id.get().to_string().as_str());
+    let moderator_roles = guild_config.map(|guild_config| config_ids(guild_config, "moderator_roles")).unwrap_or_default();
+    let moderator_users = guild_config.map(|guild_config| config_ids(guild_config, "moderator_users")).unwrap_or_default();
+    if moderator_roles.is_empty() && moderator_users.is_empty() {
+        if !native_permissions_enabled(guild_config) {
+            tracing::warn!("No moderators have been configured in server \"{}\" and native permissions are turned off.  Preventing anyone from using moderation commands", guild_name(handler, guild_id));
+        }
+        return false;
+    }
+    if member.user.as_ref().is_some_and(|user| moderator_users.contains(&(user.id.get() as i64))) {
+        return true;
+    }
+    return moderator_roles.iter().any(|mod_role_id| member.roles.iter().any(|role| role.get() == *mod_role_id as u64));
 }
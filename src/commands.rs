@@ -1,6 +1,13 @@
 
 use twilight_interactions::command::{CommandModel, CreateCommand};
-use twilight_model::id::{marker::{ChannelMarker, RoleMarker}, Id};
+use twilight_model::guild::Permissions;
+use twilight_model::id::{marker::{ChannelMarker, RoleMarker, UserMarker}, Id};
+
+/// Who Discord shows the bot's settings commands to until a server admin says otherwise under
+/// Server Settings > Integrations.  The handlers check for themselves as well.
+fn manage_guild() -> Permissions {
+    Permissions::MANAGE_GUILD
+}
 
 #[derive(CommandModel, CreateCommand)]
 #[command(autocomplete=false, name="reason", desc="Write a note to the modlog about your last moderation action.")]
@@ -11,7 +18,7 @@ pub(crate) struct ReasonCommand {
 
 
 #[derive(CommandModel, CreateCommand)]
-#[command(name="channel", desc="Set the modlog channel")]
+#[command(name="channel", desc="Set the modlog channel", default_permissions="manage_guild")]
 pub(crate) struct ChannelCommand {
     /// Modlog channel
     pub(crate) channel: Id<ChannelMarker>,
@@ -19,15 +26,324 @@ pub(crate) struct ChannelCommand {
 
 
 #[derive(CommandModel, CreateCommand)]
-#[command(name="add_moderator_role", desc="Add a moderator role to the list of mod roles.")]
+#[command(name="report_channel", desc="Set the channel member reports are sent to", default_permissions="manage_guild")]
+pub(crate) struct ReportChannelCommand {
+    /// Reports channel
+    pub(crate) channel: Id<ChannelMarker>,
+}
+
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="appeal_channel", desc="Set the channel ban appeals are sent to", default_permissions="manage_guild")]
+pub(crate) struct AppealChannelCommand {
+    /// Appeals channel
+    pub(crate) channel: Id<ChannelMarker>,
+}
+
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="add_moderator_role", desc="Add a moderator role to the list of mod roles.", default_permissions="manage_guild")]
 pub(crate) struct AddModRoleCommand {
     /// Role to give moderatorerator access to
     pub(crate) role: Id<RoleMarker>,
 }
 
 #[derive(CommandModel, CreateCommand)]
-#[command(name="delete_moderator_role", desc="Remove a moderator role from the list of mod roles.")]
+#[command(name="delete_moderator_role", desc="Remove a moderator role from the list of mod roles.", default_permissions="manage_guild")]
 pub(crate) struct DeleteModRoleCommand {
     /// Role to revoke moderator access from
     pub(crate) role: Id<RoleMarker>,
 }
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="add_moderator_user", desc="Give a single user moderator access without a role.", default_permissions="manage_guild")]
+pub(crate) struct AddModUserCommand {
+    /// User to give moderator access to
+    pub(crate) user: Id<UserMarker>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="delete_moderator_user", desc="Remove a user from the list of moderator users.", default_permissions="manage_guild")]
+pub(crate) struct DeleteModUserCommand {
+    /// User to revoke moderator access from
+    pub(crate) user: Id<UserMarker>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="modmail", desc="Manage modmail tickets")]
+pub(crate) enum ModmailCommand {
+    #[command(name="forum")]
+    Forum(ModmailForumCommand),
+    #[command(name="close")]
+    Close(ModmailCloseCommand),
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="forum", desc="Set the forum channel modmail tickets are opened in")]
+pub(crate) struct ModmailForumCommand {
+    /// Forum channel for modmail tickets
+    pub(crate) channel: Id<ChannelMarker>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="close", desc="Close the modmail ticket this command is run in")]
+pub(crate) struct ModmailCloseCommand {
+    /// Message sent to the user when their ticket is closed
+    pub(crate) reason: Option<String>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="note", desc="Private staff notes about a member")]
+pub(crate) enum NoteCommand {
+    #[command(name="add")]
+    Add(NoteAddCommand),
+    #[command(name="list")]
+    List(NoteListCommand),
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="add", desc="Add a note about a member.  Only moderators can see it.")]
+pub(crate) struct NoteAddCommand {
+    /// Member the note is about
+    pub(crate) user: Id<UserMarker>,
+    /// Text of the note
+    pub(crate) text: String,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="list", desc="Show the notes about a member")]
+pub(crate) struct NoteListCommand {
+    /// Member to show notes for
+    pub(crate) user: Id<UserMarker>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="history", desc="Show notes and moderation actions for a member")]
+pub(crate) struct HistoryCommand {
+    /// Member to show history for
+    pub(crate) user: Id<UserMarker>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="lock", desc="Stop @everyone from sending messages in a channel")]
+pub(crate) struct LockCommand {
+    /// Channel to lock.  Defaults to this one.
+    pub(crate) channel: Option<Id<ChannelMarker>>,
+    /// Unlock automatically after this long, e.g. 30m, 2h or 1d
+    pub(crate) duration: Option<String>,
+    /// Why the channel is being locked
+    pub(crate) reason: Option<String>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="unlock", desc="Undo /lock, restoring the channel's permissions exactly as they were")]
+pub(crate) struct UnlockCommand {
+    /// Channel to unlock.  Defaults to this one.
+    pub(crate) channel: Option<Id<ChannelMarker>>,
+    /// Why the channel is being unlocked
+    pub(crate) reason: Option<String>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="slowmode", desc="Set how long members must wait between messages in a channel")]
+pub(crate) struct SlowmodeCommand {
+    /// Channel to change
+    pub(crate) channel: Id<ChannelMarker>,
+    /// Seconds between messages.  0 turns slowmode off.
+    #[command(min_value=0, max_value=21600)]
+    pub(crate) seconds: i64,
+    /// Go back to the previous setting after this long, e.g. 30m, 2h or 1d
+    pub(crate) duration: Option<String>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="lockdown", desc="Lock or unlock every public channel in the server at once")]
+pub(crate) enum LockdownCommand {
+    #[command(name="start")]
+    Start(LockdownStartCommand),
+    #[command(name="end")]
+    End(LockdownEndCommand),
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="start", desc="Stop @everyone from sending messages in every public channel")]
+pub(crate) struct LockdownStartCommand {
+    /// Why the server is being locked down
+    pub(crate) reason: Option<String>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="end", desc="Restore every channel's permissions to how they were before the lockdown")]
+pub(crate) struct LockdownEndCommand {
+    /// Why the lockdown is ending
+    pub(crate) reason: Option<String>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="quarantine", desc="Take away all of a member's roles and give them the quarantine role")]
+pub(crate) struct QuarantineCommand {
+    /// Member to quarantine
+    pub(crate) user: Id<UserMarker>,
+    /// Why they are being quarantined
+    pub(crate) reason: Option<String>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="release", desc="Release a member from quarantine and give them back their old roles")]
+pub(crate) struct ReleaseCommand {
+    /// Member to release
+    pub(crate) user: Id<UserMarker>,
+    /// Why they are being released
+    pub(crate) reason: Option<String>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="quarantine_role", desc="Set the role given to quarantined members", default_permissions="manage_guild")]
+pub(crate) struct QuarantineRoleCommand {
+    /// Quarantine role.  It should only be able to see one staff channel.
+    pub(crate) role: Id<RoleMarker>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="add_sanction_role", desc="Treat a role (a mute role, say) as a punishment that is reapplied if the member rejoins.", default_permissions="manage_guild")]
+pub(crate) struct AddSanctionRoleCommand {
+    /// Role to treat as a sanction
+    pub(crate) role: Id<RoleMarker>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="delete_sanction_role", desc="Stop treating a role as a sanction.", default_permissions="manage_guild")]
+pub(crate) struct DeleteSanctionRoleCommand {
+    /// Role to stop treating as a sanction
+    pub(crate) role: Id<RoleMarker>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="nickname_policy", desc="Manage the rules member names have to follow", default_permissions="manage_guild")]
+pub(crate) enum NicknamePolicyCommand {
+    #[command(name="rules")]
+    Rules(NicknameRulesCommand),
+    #[command(name="block")]
+    Block(NicknameBlockCommand),
+    #[command(name="unblock")]
+    Unblock(NicknameUnblockCommand),
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="rules", desc="Turn name rules on or off, or show the current ones")]
+pub(crate) struct NicknameRulesCommand {
+    /// Strip characters people use to hoist themselves to the top of the member list
+    pub(crate) dehoist: Option<bool>,
+    /// Strip zalgo and invisible characters
+    pub(crate) clean: Option<bool>,
+    /// Reset names that look like a moderator's
+    pub(crate) impersonation: Option<bool>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="block", desc="Reset any name containing a word")]
+pub(crate) struct NicknameBlockCommand {
+    /// Word to block
+    pub(crate) word: String,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="unblock", desc="Stop blocking a word in names")]
+pub(crate) struct NicknameUnblockCommand {
+    /// Word to unblock
+    pub(crate) word: String,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="permissions", desc="Choose which roles can use each moderation command", default_permissions="manage_guild")]
+pub(crate) enum PermissionsCommand {
+    #[command(name="allow")]
+    Allow(PermissionsAllowCommand),
+    #[command(name="deny")]
+    Deny(PermissionsDenyCommand),
+    #[command(name="reset")]
+    Reset(PermissionsResetCommand),
+    #[command(name="show")]
+    Show(PermissionsShowCommand),
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="allow", desc="Let a role use a command")]
+pub(crate) struct PermissionsAllowCommand {
+    /// Command to change
+    pub(crate) command: String,
+    /// Role to allow
+    pub(crate) role: Id<RoleMarker>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="deny", desc="Stop a role from using a command")]
+pub(crate) struct PermissionsDenyCommand {
+    /// Command to change
+    pub(crate) command: String,
+    /// Role to deny
+    pub(crate) role: Id<RoleMarker>,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="reset", desc="Make a command open to moderators again")]
+pub(crate) struct PermissionsResetCommand {
+    /// Command to reset
+    pub(crate) command: String,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="show", desc="List commands with their own role lists")]
+pub(crate) struct PermissionsShowCommand {}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="native_permissions", desc="Let members with the matching Discord permission use moderation commands", default_permissions="manage_guild")]
+pub(crate) struct NativePermissionsCommand {
+    /// Whether Discord permissions count
+    pub(crate) enabled: bool,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="config", desc="Manage config file backups (bot owner only)", default_permissions="manage_guild")]
+pub(crate) enum ConfigCommand {
+    #[command(name="backups")]
+    Backups(ConfigBackupsCommand),
+    #[command(name="rollback")]
+    Rollback(ConfigRollbackCommand),
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="backups", desc="List the config file backups")]
+pub(crate) struct ConfigBackupsCommand {}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="rollback", desc="Restore every server's settings from a backup")]
+pub(crate) struct ConfigRollbackCommand {
+    /// Name of the backup, as shown by /config backups
+    pub(crate) backup: String,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="shadow", desc="Post what the bot would do to the modlog channel instead of doing it", default_permissions="manage_guild")]
+pub(crate) enum ShadowCommand {
+    #[command(name="server")]
+    Server(ShadowServerCommand),
+    #[command(name="rule")]
+    Rule(ShadowRuleCommand),
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="server", desc="Put every action in this server in shadow mode")]
+pub(crate) struct ShadowServerCommand {
+    /// Whether actions should only be simulated
+    pub(crate) enabled: bool,
+}
+
+#[derive(CommandModel, CreateCommand)]
+#[command(name="rule", desc="Put one automatic rule in shadow mode")]
+pub(crate) struct ShadowRuleCommand {
+    /// The rule: nickname_policy or sanction_reapply
+    pub(crate) rule: String,
+    /// Whether the rule's actions should only be simulated
+    pub(crate) enabled: bool,
+}
@@ -0,0 +1,47 @@
+use std::sync::Arc;
+
+use twilight_model::application::interaction::{Interaction, InteractionData};
+
+use crate::{alts, appeals, metrics, modmail, reports};
+use crate::state::BotHandler;
+
+// twl_fw only knows how to route slash commands and context menu commands, so button presses and
+// modal submissions come through here instead.  custom IDs are of the form "name:args", where the
+// name picks the handler and the args are whatever that handler needs to pick up where it left off.
+
+pub(crate) async fn handle(handler: Arc<BotHandler>, mut inter: Interaction) {
+    let name = match &inter.data {
+        Some(InteractionData::MessageComponent(data)) => data.custom_id.split(':').next().unwrap_or_default().to_owned(),
+        Some(InteractionData::ModalSubmit(data)) => data.custom_id.split(':').next().unwrap_or_default().to_owned(),
+        _ => "unknown".to_owned(),
+    };
+    let state = handler.state.clone();
+    let res = metrics::timed(&state, name, async move {
+        match inter.data.take() {
+            Some(InteractionData::MessageComponent(data)) => {
+                let (name, args) = data.custom_id.split_once(':').unwrap_or((&data.custom_id, ""));
+                match name {
+                    "report_action" => reports::report_action(handler, inter, args).await,
+                    "modmail_open" => modmail::choose_guild(handler, inter, args).await,
+                    "appeal" => appeals::appeal_button(handler, inter, args).await,
+                    "appeal_decision" => appeals::appeal_decision(handler, inter, args).await,
+                    "alt_ban" => alts::ban_alt(handler, inter, args).await,
+                    _ => Err(anyhow::anyhow!("Unknown component custom ID {}", data.custom_id)),
+                }
+            },
+            Some(InteractionData::ModalSubmit(data)) => {
+                let custom_id = data.custom_id.clone();
+                let (name, args) = custom_id.split_once(':').unwrap_or((&custom_id, ""));
+                match name {
+                    "report" => reports::submit_report(handler, inter, data, args).await,
+                    "appeal_submit" => appeals::appeal_modal(handler, inter, data, args).await,
+                    _ => Err(anyhow::anyhow!("Unknown modal custom ID {}", custom_id)),
+                }
+            },
+            _ => Ok(()),
+        }
+    }).await;
+    if let Err(e) = res {
+        tracing::error!("Error handling component interaction: {}", e);
+    }
+}
@@ -0,0 +1,130 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use twilight_interactions::command::CommandModel;
+use twilight_model::application::interaction::{application_command::CommandData, Interaction};
+use twilight_model::channel::message::embed::EmbedField;
+use twilight_util::builder::embed::EmbedBuilder;
+use twl_fw::response;
+
+use smb_log_format::ModLogAction;
+use crate::business_logic::{format_user, get_initiating_user, post_to_modlog, Actor};
+use crate::commands::ConfigCommand;
+use crate::disk_log::ModLogEntryExt;
+use crate::{is_bot_owner, save_config};
+use crate::state::{BotHandler, BotState};
+
+// every time the config is saved, the previous version is copied in here first, so a bad change
+// (or a bad rollback) can always be undone.
+
+const MAX_BACKUPS: usize = 20;
+
+/// The backups live in a directory next to the config file.
+fn backup_dir(state: &BotState) -> PathBuf {
+    state.config_path.parent().unwrap_or(Path::new("")).join("config_backups")
+}
+
+/// Copy the config file into the backup directory under a timestamped name, and delete the oldest
+/// backups past MAX_BACKUPS.
+pub(crate) fn back_up(state: &BotState) -> std::io::Result<()> {
+    let config_path = &state.config_path;
+    if !config_path.exists() {
+        return Ok(());
+    }
+    let backup_dir = backup_dir(state);
+    std::fs::create_dir_all(&backup_dir)?;
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    let name = format!("config-{}.{:03}.toml", now.as_secs(), now.subsec_millis());
+    std::fs::copy(config_path, backup_dir.join(name))?;
+
+    for old in list_backups(state)?.iter().skip(MAX_BACKUPS) {
+        std::fs::remove_file(backup_dir.join(old))?;
+    }
+    Ok(())
+}
+
+/// The file names of every backup, newest first.
+fn list_backups(state: &BotState) -> std::io::Result<Vec<String>> {
+    let mut backups = match std::fs::read_dir(backup_dir(state)) {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.ok()?.file_name().into_string().ok())
+            .filter(|name| name.starts_with("config-") && name.ends_with(".toml"))
+            .collect::<Vec<_>>(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    // the timestamps are all the same length, so sorting by name sorts by age
+    backups.sort_unstable_by(|a, b| b.cmp(a));
+    Ok(backups)
+}
+
+/// The unix timestamp a backup was taken at, from its file name.
+fn backup_time(name: &str) -> Option<u64> {
+    name.strip_prefix("config-")?.split('.').next()?.parse().ok()
+}
+
+// ditto update_config()
+fn restore(state: &BotState, document: toml_edit::Document) {
+    let mut config = state.config.lock().unwrap();
+    *config = document;
+    std::mem::drop(config);
+    save_config(state);
+}
+
+pub(crate) async fn config(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let user = get_initiating_user(&inter)?;
+    // the config covers every server the bot is in, so this can't be left up to any one of them
+    if !is_bot_owner(&handler.state, user.id) {
+        response!(ephemeral; handler, inter, "Only the bot's owner can use that command.");
+        return Ok(());
+    }
+
+    match ConfigCommand::from_interaction(data.into())? {
+        ConfigCommand::Backups(_) => {
+            let backups = list_backups(&handler.state)?;
+            if backups.is_empty() {
+                response!(ephemeral; handler, inter, "There are no config backups yet.");
+            } else {
+                let list = backups.iter()
+                    .map(|name| match backup_time(name) {
+                        Some(time) => format!("`{}` (<t:{}:f>)", name, time),
+                        None => format!("`{}`", name),
+                    })
+                    .collect::<Vec<_>>()
+                    .join("\n");
+                response!(ephemeral; handler, inter, "Backups, newest first:\n{}", list);
+            }
+        },
+        ConfigCommand::Rollback(cmd) => {
+            // only ever read files that are actually in the list, so this can't be pointed at
+            // anything else on disk
+            if !list_backups(&handler.state)?.contains(&cmd.backup) {
+                response!(ephemeral; handler, inter, "There is no backup called `{}`.  Use /config backups to see them.", cmd.backup);
+                return Ok(());
+            }
+            let contents = std::fs::read_to_string(backup_dir(&handler.state).join(&cmd.backup))?;
+            let document: toml_edit::Document = match contents.parse() {
+                Ok(document) => document,
+                Err(e) => {
+                    response!(ephemeral; handler, inter, "`{}` is not valid TOML ({}).  Nothing was changed.", cmd.backup, e);
+                    return Ok(());
+                }
+            };
+            restore(&handler.state, document);
+
+            Actor::User(user).log_entry(None, ModLogAction::ConfigRollback {
+                backup: cmd.backup.clone(),
+            }).log(&handler.state);
+            if let Some(guild_id) = inter.guild_id {
+                let builder = EmbedBuilder::new()
+                    .title("Configuration rolled back")
+                    .description(format!("Every server's settings were restored from `{}`.", cmd.backup))
+                    .field(EmbedField {name: "Rolled back by".to_string(), value: format_user(user), inline: false});
+                post_to_modlog(&handler, guild_id, builder.build()).await?;
+            }
+            response!(ephemeral; handler, inter, "Config restored from `{}`.  The config it replaced was backed up first, so this can be undone the same way.", cmd.backup);
+        },
+    }
+    Ok(())
+}
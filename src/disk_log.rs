@@ -1,31 +1,40 @@
-use std::sync::Mutex;
-use std::fs::File;
 use std::io::Write;
 
 use std::time::SystemTime;
 
-use once_cell::sync::Lazy;
-use twilight_model::{user::User, id::{Id, marker::ChannelMarker}, channel::Message};
+use twilight_model::{user::{CurrentUser, User}, id::{Id, marker::ChannelMarker}, channel::Message};
 
-use crate::get_output_path;
+use crate::state::BotState;
 
 use smb_log_format::{ModLogEntry, ModLogAction, ModLogMessage};
 
-static LOGFILE: Lazy<Option<Mutex<File>>> = Lazy::new(|| {
-    let a = std::fs::OpenOptions::new().create(true).truncate(false).append(true).open(get_output_path().join("modlog.ndjson")).map(Into::into);
-    if let Err(e) = &a {
-        tracing::warn!("Unable to open logfile! Error message was: {}  Modlog will be written to Discord only!", e);
+/// Make sure everything logged so far has actually hit the disk.
+pub(crate) fn flush(state: &BotState) {
+    if let Some(logfile) = &state.logfile {
+        if let Err(e) = logfile.lock().unwrap().sync_all() {
+            tracing::error!("Error flushing logfile! {}", e);
+        }
+    }
+}
+
+/// The name of an action's ModLogAction variant, for counting them.
+fn action_name(action: &ModLogAction) -> String {
+    // serde writes enums as either "Variant" or {"Variant": ...}
+    match serde_json::to_value(action) {
+        Ok(serde_json::Value::String(name)) => name,
+        Ok(serde_json::Value::Object(fields)) => fields.keys().next().cloned().unwrap_or_default(),
+        _ => "unknown".to_owned(),
     }
-    a.ok()
-});
+}
 
 pub trait ModLogEntryExt {
     fn new(moderator: &User, channel: Option<Id<ChannelMarker>>, timestamp: SystemTime, action: ModLogAction) -> Self;
-    fn log(&self);
+    fn new_by_bot(bot: &CurrentUser, channel: Option<Id<ChannelMarker>>, timestamp: SystemTime, action: ModLogAction) -> Self;
+    fn log(&self, state: &BotState);
 }
 
 pub trait ModLogMessageExt {
-    async fn from_message(msg: &Message) -> Self;
+    async fn from_message(state: &BotState, msg: &Message) -> Self;
 }
 
 impl ModLogEntryExt for ModLogEntry {
@@ -39,8 +48,19 @@ impl ModLogEntryExt for ModLogEntry {
             action,
         }
     }
-    fn log(&self) {
-        if let Some(logfile) = &*LOGFILE {
+    fn new_by_bot(bot_user: &CurrentUser, channel_id: Option<Id<ChannelMarker>>, timestamp: SystemTime, action: ModLogAction) -> Self {
+        Self {
+            channel_id: channel_id.map(|x|x.get()).unwrap_or(0),
+            moderator_id: bot_user.id.get(),
+            moderator_name: bot_user.name.clone(),
+            moderator_discrim: bot_user.discriminator,
+            timestamp,
+            action,
+        }
+    }
+    fn log(&self, state: &BotState) {
+        state.metrics.count_action(&action_name(&self.action));
+        if let Some(logfile) = &state.logfile {
             let mut logfile = logfile.lock().unwrap(); // .lock() will only fail if another thread panicked wile holding the mutex
             if let Err(e) = self.write(&mut *logfile){
                 tracing::error!("Error writing moderation action to logfile! {}", e);
@@ -51,10 +71,10 @@ impl ModLogEntryExt for ModLogEntry {
 }
 
 impl ModLogMessageExt for ModLogMessage {
-    async fn from_message(message: &Message) -> Self {
+    async fn from_message(state: &BotState, message: &Message) -> Self {
         let mut attachments = Vec::new();
         if !message.attachments.is_empty() {
-            let path = get_output_path().join(message.id.to_string());
+            let path = state.output_path.join(message.id.to_string());
             let _ = std::fs::create_dir(&path);
             for attachment in message.attachments.iter() {
                 let res = download_file(&attachment.proxy_url, path.join(&attachment.filename)).await;
@@ -77,7 +97,7 @@ impl ModLogMessageExt for ModLogMessage {
     }
 }
 
-async fn download_file(url: &str, filename: std::path::PathBuf) -> anyhow::Result<()> {
+pub(crate) async fn download_file(url: &str, filename: std::path::PathBuf) -> anyhow::Result<()> {
     let mut resp = reqwest::get(url).await?;
     let mut out = std::fs::File::create(filename)?;
     while let Some(data) = resp.chunk().await? {
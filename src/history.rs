@@ -0,0 +1,157 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Deserialize};
+use twilight_interactions::command::CommandModel;
+use twilight_model::application::interaction::{application_command::CommandData, Interaction};
+use twilight_model::id::Id;
+use twilight_model::id::marker::{GuildMarker, UserMarker};
+use twilight_util::builder::embed::EmbedBuilder;
+use twl_fw::response;
+
+use smb_log_format::{ModLogEntry, ModLogAction};
+use crate::business_logic::{get_guild, get_initiating_user, is_allowed_to_use, respond_with_embed};
+use crate::commands::{HistoryCommand, NoteCommand};
+use crate::disk_log::ModLogEntryExt;
+use crate::persist::Persisted;
+use crate::state::{BotHandler, BotState};
+
+// modlog.ndjson has no idea which guild an entry belongs to or who it was done to, so we keep a
+// separate per-guild, per-user index of everything that's happened to each member.
+
+/// guild ID -> user ID -> cases, oldest first
+static HISTORY: Persisted<HashMap<u64, HashMap<u64, Vec<Case>>>> = Persisted::new("history.msgpack");
+
+/// Embed descriptions can't be longer than this.
+const MAX_DESCRIPTION_LENGTH: usize = 4096;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub(crate) struct Case {
+    pub(crate) timestamp: SystemTime,
+    /// None if we don't know who did it, e.g. a ban done through the Discord client.
+    pub(crate) moderator_id: Option<u64>,
+    pub(crate) kind: CaseKind,
+    pub(crate) detail: String,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+pub(crate) enum CaseKind {
+    Note,
+    DeleteMessage,
+    Warn,
+    Timeout,
+    Kick,
+    Ban,
+    Unban,
+    Roles,
+    Quarantine,
+    Release,
+    SanctionEvasion,
+    Automod,
+    Nickname,
+}
+
+impl CaseKind {
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            CaseKind::Note => "Note",
+            CaseKind::DeleteMessage => "Message deleted",
+            CaseKind::Warn => "Warned",
+            CaseKind::Timeout => "Timed out",
+            CaseKind::Kick => "Kicked",
+            CaseKind::Ban => "Banned",
+            CaseKind::Unban => "Unbanned",
+            CaseKind::Roles => "Roles changed",
+            CaseKind::Quarantine => "Quarantined",
+            CaseKind::Release => "Released from quarantine",
+            CaseKind::SanctionEvasion => "Sanction evasion",
+            CaseKind::Automod => "Automod",
+            CaseKind::Nickname => "Nickname changed",
+        }
+    }
+}
+
+/// Add a case to a member's history.
+pub(crate) fn record(state: &BotState, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>, moderator_id: Option<Id<UserMarker>>, kind: CaseKind, detail: impl Into<String>) {
+    let case = Case {
+        timestamp: SystemTime::now(),
+        moderator_id: moderator_id.map(Id::get),
+        kind,
+        detail: detail.into(),
+    };
+    HISTORY.update(state, |history| history.entry(guild_id.get()).or_default().entry(user_id.get()).or_default().push(case));
+}
+
+/// Everything on record about a member, oldest first.
+pub(crate) fn cases(state: &BotState, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>) -> Vec<Case> {
+    HISTORY.read(state, |history| history.get(&guild_id.get()).and_then(|guild| guild.get(&user_id.get())).cloned().unwrap_or_default())
+}
+
+/// One line per case, stopping before the result gets longer than `max_length`.
+pub(crate) fn format_cases<'a>(cases: impl Iterator<Item=&'a Case>, max_length: usize) -> String {
+    let mut out = String::new();
+    for case in cases {
+        let when = case.timestamp.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let by = case.moderator_id.map(|id| format!(" by <@{}>", id)).unwrap_or_default();
+        let line = format!("<t:{}:d> **{}**{}: {}\n", when, case.kind.label(), by, case.detail);
+        if out.len() + line.len() > max_length {
+            break;
+        }
+        out.push_str(&line);
+    }
+    out
+}
+
+pub(crate) async fn note(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "note")) {
+        response!(ephemeral; handler, inter, "You do not have permission to use that command.");
+        return Ok(());
+    }
+
+    // notes are for staff eyes only, so every response here is ephemeral and nothing goes to the
+    // modlog channel
+    match NoteCommand::from_interaction(data.into())? {
+        NoteCommand::Add(cmd) => {
+            record(&handler.state, guild_id, cmd.user, Some(moderator_user.id), CaseKind::Note, cmd.text.clone());
+            ModLogEntry::new(moderator_user, None, SystemTime::now(), ModLogAction::Note {
+                user_id: cmd.user.get(),
+                text: cmd.text,
+            }).log(&handler.state);
+            response!(ephemeral; handler, inter, "Note added to <@{}>.", cmd.user);
+        },
+        NoteCommand::List(cmd) => {
+            let cases = cases(&handler.state, guild_id, cmd.user);
+            let notes = format_cases(cases.iter().rev().filter(|case| case.kind == CaseKind::Note), MAX_DESCRIPTION_LENGTH - 64);
+            if notes.is_empty() {
+                response!(ephemeral; handler, inter, "There are no notes on <@{}>.", cmd.user);
+            } else {
+                let embed = EmbedBuilder::new().title("Notes").description(format!("<@{}>\n{}", cmd.user, notes)).build();
+                respond_with_embed(&handler, &inter, embed).await?;
+            }
+        },
+    }
+    Ok(())
+}
+
+pub(crate) async fn history(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "history")) {
+        response!(ephemeral; handler, inter, "You do not have permission to use that command.");
+        return Ok(());
+    }
+
+    let cmd = HistoryCommand::from_interaction(data.into())?;
+    let cases = cases(&handler.state, guild_id, cmd.user);
+    if cases.is_empty() {
+        response!(ephemeral; handler, inter, "<@{}> has a clean record.", cmd.user);
+    } else {
+        let embed = EmbedBuilder::new().title("History").description(format!("<@{}>\n{}", cmd.user, format_cases(cases.iter().rev(), MAX_DESCRIPTION_LENGTH - 64))).build();
+        respond_with_embed(&handler, &inter, embed).await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,308 @@
+use std::collections::BTreeMap;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde::{Serialize, Deserialize};
+use twilight_interactions::command::CommandModel;
+use twilight_model::application::interaction::{application_command::CommandData, Interaction};
+use twilight_model::channel::ChannelType;
+use twilight_model::channel::message::Embed;
+use twilight_model::guild::Permissions;
+use twilight_model::id::Id;
+use twilight_model::id::marker::{ChannelMarker, GuildMarker, MessageMarker};
+use twilight_util::builder::embed::EmbedBuilder;
+use twl_fw::response;
+
+use smb_log_format::ModLogAction;
+use crate::actions::{self, Action};
+use crate::business_logic::{bot_user, get_guild, get_initiating_user, get_modlog_channel, is_allowed_to_use, Actor};
+use crate::commands::LockdownCommand;
+use crate::disk_log::ModLogEntryExt;
+use crate::locks::{is_locked, restore_overwrite, OverwriteSnapshot};
+use crate::persist::Persisted;
+use crate::state::{BotHandler, BotState};
+
+/// How many channels to get through between updates of the progress message in the modlog channel.
+const PROGRESS_INTERVAL: usize = 10;
+
+/// Lockdowns in progress, keyed by guild ID.  This is written after every single channel so that
+/// if the bot goes down partway through starting or ending a lockdown, it can carry on where it
+/// left off instead of losing track of what the permissions used to be.
+static LOCKDOWNS: Persisted<HashMap<u64, Lockdown>> = Persisted::new("lockdowns.msgpack");
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Lockdown {
+    phase: Phase,
+    reason: String,
+    /// channel ID -> what we found there before the lockdown.  a BTreeMap so we go through the
+    /// channels in the same order every time.
+    channels: BTreeMap<u64, LockdownChannel>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+enum Phase {
+    Starting,
+    Active,
+    Ending,
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy)]
+struct LockdownChannel {
+    original: Option<OverwriteSnapshot>,
+    locked: bool,
+}
+
+pub(crate) async fn lockdown(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "lockdown")) {
+        response!(ephemeral; handler, inter, "You do not have permission to use that command.");
+        return Ok(());
+    }
+
+    let current_phase = LOCKDOWNS.read(&handler.state, |lockdowns| lockdowns.get(&guild_id.get()).map(|lockdown| lockdown.phase));
+
+    match LockdownCommand::from_interaction(data.into())? {
+        LockdownCommand::Start(cmd) => {
+            if current_phase.is_some() {
+                response!(ephemeral; handler, inter, "This server is already locked down.");
+                return Ok(());
+            }
+            let reason = cmd.reason.unwrap_or_else(|| "No reason given.".to_owned());
+
+            // snapshot everything before we change anything
+            let channels = handler.client.guild_channels(guild_id).await?.models().await?;
+            let channels = channels.iter()
+                .filter(|channel| matches!(channel.kind, ChannelType::GuildText | ChannelType::GuildAnnouncement | ChannelType::GuildForum))
+                .filter_map(|channel| {
+                    let original = OverwriteSnapshot::from_channel(guild_id, channel);
+                    // channels @everyone can't see aren't public, so leave them alone
+                    if original.is_some_and(|overwrite| overwrite.denies(Permissions::VIEW_CHANNEL)) {
+                        return None;
+                    }
+                    // channels locked with /lock already can't be sent in, and are left to /unlock
+                    // or their timer.  if the lockdown took them over as well, whichever finished
+                    // first would put back permissions the other one still relies on.
+                    if is_locked(&handler.state, channel.id) {
+                        return None;
+                    }
+                    Some((channel.id.get(), LockdownChannel {original, locked: false}))
+                })
+                .collect::<BTreeMap<_, _>>();
+            let count = channels.len();
+            let describe = || format!("Lock down the server: stop @everyone sending messages in {}", channels.keys().map(|id| format!("<#{}>", id)).collect::<Vec<_>>().join(" "));
+            if actions::simulate_if_shadowed(&handler, guild_id, None, &Actor::User(moderator_user), describe).await? {
+                response!(ephemeral; handler, inter, "Shadow mode is on, so the server was not locked down.  What would have happened has been posted to the modlog channel.");
+                return Ok(());
+            }
+            LOCKDOWNS.update(&handler.state, |lockdowns| lockdowns.insert(guild_id.get(), Lockdown {phase: Phase::Starting, reason: reason.clone(), channels}));
+
+            // this takes a while, and discord wants to hear back from us within three seconds
+            response!(ephemeral; handler, inter, "Locking down {} channels.  Progress will be posted in the modlog channel.", count);
+            run_start(&handler, guild_id, Actor::User(moderator_user)).await
+        },
+        LockdownCommand::End(cmd) => {
+            if current_phase != Some(Phase::Active) {
+                response!(ephemeral; handler, inter, "This server is not locked down.");
+                return Ok(());
+            }
+            let reason = cmd.reason.unwrap_or_else(|| "No reason given.".to_owned());
+            LOCKDOWNS.update(&handler.state, |lockdowns| {
+                if let Some(lockdown) = lockdowns.get_mut(&guild_id.get()) {
+                    lockdown.phase = Phase::Ending;
+                    lockdown.reason = reason;
+                }
+            });
+            response!(ephemeral; handler, inter, "Ending the lockdown.  Progress will be posted in the modlog channel.");
+            run_end(&handler, guild_id, Actor::User(moderator_user)).await
+        },
+    }
+}
+
+/// Lock every channel in the guild's lockdown that isn't locked yet.
+async fn run_start(handler: &BotHandler, guild_id: Id<GuildMarker>, actor: Actor<'_>) -> anyhow::Result<()> {
+    let Some(lockdown) = LOCKDOWNS.read(&handler.state, |lockdowns| lockdowns.get(&guild_id.get()).cloned()) else {
+        return Ok(());
+    };
+    let mut progress = Progress::new(handler, guild_id, "Locking down server", lockdown.channels.len()).await;
+    let mut failed = Vec::new();
+
+    for (channel_id, channel) in lockdown.channels.iter() {
+        let channel_id: Id<ChannelMarker> = Id::new(*channel_id);
+        if !channel.locked {
+            // shadow mode was checked before the lockdown started, but it may have been turned on
+            // since if we're resuming one
+            match actions::execute(handler, guild_id, None, &actor, Action::LockChannel {channel_id, original: channel.original}).await {
+                Ok(true) => set_locked(&handler.state, guild_id, channel_id, true),
+                Ok(false) => failed.push(channel_id),
+                Err(e) => {
+                    tracing::warn!("Couldn't lock channel {} during lockdown: {}", channel_id, e);
+                    failed.push(channel_id);
+                },
+            }
+        }
+        progress.tick(handler).await;
+    }
+
+    LOCKDOWNS.update(&handler.state, |lockdowns| {
+        if let Some(lockdown) = lockdowns.get_mut(&guild_id.get()) {
+            lockdown.phase = Phase::Active;
+        }
+    });
+
+    let locked = lockdown.channels.keys().copied().filter(|id| !failed.contains(&Id::new(*id))).collect::<Vec<_>>();
+    actor.log_entry(None, ModLogAction::LockdownStart {
+        reason: lockdown.reason.clone(),
+        channels: locked.clone(),
+    }).log(&handler.state);
+    progress.finish(handler, &format!("Locked {} channels.  Started by {}.\nReason: {}", locked.len(), actor.describe(), lockdown.reason), &failed).await;
+    Ok(())
+}
+
+/// Restore every channel in the guild's lockdown that is still locked, then forget the lockdown.
+async fn run_end(handler: &BotHandler, guild_id: Id<GuildMarker>, actor: Actor<'_>) -> anyhow::Result<()> {
+    let Some(lockdown) = LOCKDOWNS.read(&handler.state, |lockdowns| lockdowns.get(&guild_id.get()).cloned()) else {
+        return Ok(());
+    };
+    let mut progress = Progress::new(handler, guild_id, "Ending lockdown", lockdown.channels.len()).await;
+    let mut failed = Vec::new();
+
+    for (channel_id, channel) in lockdown.channels.iter() {
+        let channel_id: Id<ChannelMarker> = Id::new(*channel_id);
+        if channel.locked {
+            match restore_overwrite(handler, guild_id, channel_id, channel.original).await {
+                Ok(()) => set_locked(&handler.state, guild_id, channel_id, false),
+                Err(e) => {
+                    tracing::warn!("Couldn't restore channel {} after lockdown: {}", channel_id, e);
+                    failed.push(channel_id);
+                },
+            }
+        }
+        progress.tick(handler).await;
+    }
+
+    if failed.is_empty() {
+        LOCKDOWNS.update(&handler.state, |lockdowns| lockdowns.remove(&guild_id.get()));
+    } else {
+        // keep the snapshots of the ones we couldn't restore around, so they can be retried with
+        // another /lockdown end
+        LOCKDOWNS.update(&handler.state, |lockdowns| {
+            if let Some(lockdown) = lockdowns.get_mut(&guild_id.get()) {
+                lockdown.phase = Phase::Active;
+                lockdown.channels.retain(|_, channel| channel.locked);
+            }
+        });
+    }
+
+    let restored = lockdown.channels.iter().filter(|(_, channel)| channel.locked).map(|(id, _)| *id).filter(|id| !failed.contains(&Id::new(*id))).collect::<Vec<_>>();
+    actor.log_entry(None, ModLogAction::LockdownEnd {
+        reason: lockdown.reason.clone(),
+        channels: restored.clone(),
+    }).log(&handler.state);
+    progress.finish(handler, &format!("Restored {} channels.  Ended by {}.\nReason: {}", restored.len(), actor.describe(), lockdown.reason), &failed).await;
+    Ok(())
+}
+
+/// Whether a channel is one the guild's lockdown has locked, or is about to.
+pub(crate) fn is_locked_down(state: &BotState, guild_id: Id<GuildMarker>, channel_id: Id<ChannelMarker>) -> bool {
+    LOCKDOWNS.read(state, |lockdowns| lockdowns.get(&guild_id.get()).is_some_and(|lockdown| lockdown.channels.contains_key(&channel_id.get())))
+}
+
+fn set_locked(state: &BotState, guild_id: Id<GuildMarker>, channel_id: Id<ChannelMarker>, locked: bool) {
+    LOCKDOWNS.update(state, |lockdowns| {
+        if let Some(channel) = lockdowns.get_mut(&guild_id.get()).and_then(|lockdown| lockdown.channels.get_mut(&channel_id.get())) {
+            channel.locked = locked;
+        }
+    });
+}
+
+/// Carry on with any lockdowns that were halfway through starting or ending when the bot last shut
+/// down.
+pub(crate) fn resume(handler: Arc<BotHandler>) {
+    let unfinished = LOCKDOWNS.read(&handler.state, |lockdowns| lockdowns.iter()
+        .filter(|(_, lockdown)| lockdown.phase != Phase::Active)
+        .map(|(guild_id, lockdown)| (Id::new(*guild_id), lockdown.phase))
+        .collect::<Vec<_>>());
+    for (guild_id, phase) in unfinished {
+        let handler = handler.clone();
+        handler.state.clone().spawn_untracked(async move {
+            tracing::info!("Resuming interrupted lockdown in guild {}", guild_id);
+            let res = match bot_user(&handler) {
+                Ok(bot) if phase == Phase::Starting => run_start(&handler, guild_id, Actor::Bot(bot)).await,
+                Ok(bot) => run_end(&handler, guild_id, Actor::Bot(bot)).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = res {
+                tracing::error!("Error resuming lockdown in guild {}: {}", guild_id, e);
+            }
+        });
+    }
+}
+
+/// A message in the modlog channel that we keep editing as a lockdown makes its way through the
+/// channels.
+struct Progress {
+    message: Option<(Id<ChannelMarker>, Id<MessageMarker>)>,
+    title: &'static str,
+    done: usize,
+    total: usize,
+}
+
+impl Progress {
+    async fn new(handler: &BotHandler, guild_id: Id<GuildMarker>, title: &'static str, total: usize) -> Self {
+        let mut progress = Self {message: None, title, done: 0, total};
+        if let Some(modlog_channel_id) = get_modlog_channel(&handler.state, guild_id) {
+            let _pending = handler.state.metrics.modlog_post();
+            let res = match handler.client.create_message(modlog_channel_id).embeds(&[progress.embed(None)]) {
+                Ok(request) => match request.await {
+                    Ok(resp) => resp.model().await.map_err(anyhow::Error::from),
+                    Err(e) => Err(e.into()),
+                },
+                Err(e) => Err(e.into()),
+            };
+            match res {
+                Ok(message) => progress.message = Some((modlog_channel_id, message.id)),
+                Err(e) => tracing::warn!("Couldn't post lockdown progress: {}", e),
+            }
+        }
+        progress
+    }
+
+    fn embed(&self, description: Option<String>) -> Embed {
+        EmbedBuilder::new()
+            .title(format!("{} ({}/{})", self.title, self.done, self.total))
+            .description(description.unwrap_or_else(|| "Working...".to_owned()))
+            .build()
+    }
+
+    async fn tick(&mut self, handler: &BotHandler) {
+        self.done += 1;
+        if self.done % PROGRESS_INTERVAL == 0 {
+            self.update(handler, None).await;
+        }
+    }
+
+    async fn finish(&mut self, handler: &BotHandler, summary: &str, failed: &[Id<ChannelMarker>]) {
+        let mut description = summary.to_owned();
+        if !failed.is_empty() {
+            description.push_str("\nCould not change: ");
+            description.push_str(&failed.iter().map(|id| format!("<#{}>", id)).collect::<Vec<_>>().join(" "));
+        }
+        self.update(handler, Some(description)).await;
+    }
+
+    async fn update(&self, handler: &BotHandler, description: Option<String>) {
+        let Some((channel_id, message_id)) = self.message else {
+            return;
+        };
+        let embeds = [self.embed(description)];
+        let res = match handler.client.update_message(channel_id, message_id).embeds(Some(&embeds)) {
+            Ok(request) => request.await.map(|_| ()).map_err(anyhow::Error::from),
+            Err(e) => Err(e.into()),
+        };
+        if let Err(e) = res {
+            tracing::warn!("Couldn't update lockdown progress: {}", e);
+        }
+    }
+}
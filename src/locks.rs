@@ -0,0 +1,375 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Deserialize};
+use twilight_interactions::command::CommandModel;
+use twilight_model::application::interaction::{application_command::CommandData, Interaction};
+use twilight_model::channel::Channel;
+use twilight_model::channel::message::embed::EmbedField;
+use twilight_model::channel::permission_overwrite::PermissionOverwriteType;
+use twilight_model::guild::Permissions;
+use twilight_model::http::permission_overwrite::PermissionOverwrite;
+use twilight_model::id::Id;
+use twilight_model::id::marker::{ChannelMarker, GuildMarker};
+use twilight_util::builder::embed::EmbedBuilder;
+use twl_fw::response;
+
+use smb_log_format::ModLogAction;
+use crate::actions::{self, Action};
+use crate::business_logic::{bot_user, get_guild, get_initiating_user, is_allowed_to_use, parse_duration, post_to_modlog, Actor};
+use crate::commands::{LockCommand, SlowmodeCommand, UnlockCommand};
+use crate::disk_log::ModLogEntryExt;
+use crate::lockdown;
+use crate::persist::Persisted;
+use crate::state::{BotHandler, BotState};
+
+/// Everything /lock takes away from @everyone.
+const LOCKED_PERMISSIONS: Permissions = Permissions::SEND_MESSAGES.union(Permissions::SEND_MESSAGES_IN_THREADS);
+
+/// Channels locked with /lock, keyed by channel ID.
+static LOCKED_CHANNELS: Persisted<HashMap<u64, LockedChannel>> = Persisted::new("locked_channels.msgpack");
+/// Channels whose slowmode was changed with a duration, keyed by channel ID.
+static SLOWMODES: Persisted<HashMap<u64, SlowmodeChange>> = Persisted::new("slowmodes.msgpack");
+
+/// The @everyone overwrite on a channel, as raw permission bits.
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct OverwriteSnapshot {
+    allow: u64,
+    deny: u64,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct LockedChannel {
+    guild_id: u64,
+    /// None if the channel had no @everyone overwrite at all before it was locked.
+    original: Option<OverwriteSnapshot>,
+    revert_at: Option<SystemTime>,
+}
+
+#[derive(Serialize, Deserialize, Clone)]
+struct SlowmodeChange {
+    guild_id: u64,
+    original: u16,
+    revert_at: SystemTime,
+}
+
+impl OverwriteSnapshot {
+    /// Find the @everyone overwrite in a channel's list of overwrites.
+    pub(crate) fn from_channel(guild_id: Id<GuildMarker>, channel: &Channel) -> Option<Self> {
+        channel.permission_overwrites.as_deref().unwrap_or_default().iter()
+            .find(|overwrite| overwrite.kind == PermissionOverwriteType::Role && overwrite.id == guild_id.cast())
+            .map(|overwrite| OverwriteSnapshot {allow: overwrite.allow.bits(), deny: overwrite.deny.bits()})
+    }
+
+    pub(crate) fn denies(&self, permissions: Permissions) -> bool {
+        Permissions::from_bits_truncate(self.deny).contains(permissions)
+    }
+}
+
+/// Whether a channel is locked with /lock.
+pub(crate) fn is_locked(state: &BotState, channel_id: Id<ChannelMarker>) -> bool {
+    LOCKED_CHANNELS.read(state, |locked| locked.contains_key(&channel_id.get()))
+}
+
+/// Read a channel's current @everyone overwrite.
+pub(crate) async fn everyone_overwrite(handler: &BotHandler, guild_id: Id<GuildMarker>, channel_id: Id<ChannelMarker>) -> anyhow::Result<Option<OverwriteSnapshot>> {
+    let channel = handler.client.channel(channel_id).await?.model().await?;
+    Ok(OverwriteSnapshot::from_channel(guild_id, &channel))
+}
+
+/// Deny sending messages to @everyone, keeping the rest of the existing overwrite as it was.
+pub(crate) async fn deny_sending(handler: &BotHandler, guild_id: Id<GuildMarker>, channel_id: Id<ChannelMarker>, original: Option<OverwriteSnapshot>) -> anyhow::Result<()> {
+    let original = original.unwrap_or(OverwriteSnapshot {allow: 0, deny: 0});
+    let overwrite = PermissionOverwrite {
+        allow: Some(Permissions::from_bits_truncate(original.allow) - LOCKED_PERMISSIONS),
+        deny: Some(Permissions::from_bits_truncate(original.deny) | LOCKED_PERMISSIONS),
+        id: guild_id.cast(),
+        kind: PermissionOverwriteType::Role,
+    };
+    handler.client.update_channel_permission(channel_id, &overwrite).await?;
+    Ok(())
+}
+
+/// Put a channel's @everyone overwrite back exactly the way it was, including removing it if there
+/// wasn't one.
+pub(crate) async fn restore_overwrite(handler: &BotHandler, guild_id: Id<GuildMarker>, channel_id: Id<ChannelMarker>, original: Option<OverwriteSnapshot>) -> anyhow::Result<()> {
+    match original {
+        Some(original) => {
+            let overwrite = PermissionOverwrite {
+                allow: Some(Permissions::from_bits_truncate(original.allow)),
+                deny: Some(Permissions::from_bits_truncate(original.deny)),
+                id: guild_id.cast(),
+                kind: PermissionOverwriteType::Role,
+            };
+            handler.client.update_channel_permission(channel_id, &overwrite).await?;
+        },
+        None => {
+            handler.client.delete_channel_permission(channel_id).role(guild_id.cast()).await?;
+        },
+    }
+    Ok(())
+}
+
+pub(crate) async fn lock(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "lock")) {
+        response!(ephemeral; handler, inter, "You do not have permission to use that command.");
+        return Ok(());
+    }
+
+    let cmd = LockCommand::from_interaction(data.into())?;
+    let Some(channel_id) = cmd.channel.or(inter.channel.as_ref().map(|channel| channel.id)) else {
+        response!(ephemeral; handler, inter, "Please specify a channel.");
+        return Ok(());
+    };
+    let duration = match cmd.duration.as_deref().map(parse_duration) {
+        Some(None) => {
+            response!(ephemeral; handler, inter, "I don't understand that duration.  Try something like 30m, 2h or 1d.");
+            return Ok(());
+        },
+        Some(Some(duration)) => Some(duration),
+        None => None,
+    };
+    let revert_at = match duration.map(|duration| SystemTime::now().checked_add(duration)) {
+        Some(None) => {
+            response!(ephemeral; handler, inter, "That duration is too long.");
+            return Ok(());
+        },
+        Some(Some(revert_at)) => Some(revert_at),
+        None => None,
+    };
+    let reason = cmd.reason.unwrap_or_else(|| "No reason given.".to_owned());
+
+    if is_locked(&handler.state, channel_id) {
+        response!(ephemeral; handler, inter, "<#{}> is already locked.", channel_id);
+        return Ok(());
+    }
+    // the lockdown's snapshot is from before it locked the channel, and ending it would undo this
+    // lock while leaving it on record
+    if lockdown::is_locked_down(&handler.state, guild_id, channel_id) {
+        response!(ephemeral; handler, inter, "<#{}> is part of the server lockdown.  End that with /lockdown end first.", channel_id);
+        return Ok(());
+    }
+
+    let original = everyone_overwrite(&handler, guild_id, channel_id).await?;
+    // save the snapshot before touching anything, so if we die halfway through /unlock still works
+    LOCKED_CHANNELS.update(&handler.state, |locked| locked.insert(channel_id.get(), LockedChannel {guild_id: guild_id.get(), original, revert_at}));
+    match actions::execute(&handler, guild_id, None, &Actor::User(moderator_user), Action::LockChannel {channel_id, original}).await {
+        Ok(true) => (),
+        // either way nothing was changed, so there's nothing for /unlock to put back
+        Ok(false) => {
+            LOCKED_CHANNELS.update(&handler.state, |locked| locked.remove(&channel_id.get()));
+            response!(ephemeral; handler, inter, "Shadow mode is on, so <#{}> was not locked.  What would have happened has been posted to the modlog channel.", channel_id);
+            return Ok(());
+        },
+        Err(e) => {
+            LOCKED_CHANNELS.update(&handler.state, |locked| locked.remove(&channel_id.get()));
+            response!(ephemeral; handler, inter, "Couldn't lock <#{}>.  Error was: {}", channel_id, e);
+            return Ok(());
+        },
+    }
+
+    Actor::User(moderator_user).log_entry(Some(channel_id), ModLogAction::ChannelLock {
+        channel_id: channel_id.get(),
+        until: revert_at,
+        reason: reason.clone(),
+    }).log(&handler.state);
+
+    let mut builder = EmbedBuilder::new()
+        .title("Channel locked")
+        .description(reason)
+        .field(EmbedField {name: "Channel".to_string(), value: format!("<#{}>", channel_id), inline: false})
+        .field(EmbedField {name: "Locked by".to_string(), value: Actor::User(moderator_user).describe(), inline: false});
+    if let Some(revert_at) = revert_at {
+        builder = builder.field(EmbedField {name: "Until".to_string(), value: format!("<t:{}:f>", revert_at.duration_since(UNIX_EPOCH)?.as_secs()), inline: false});
+        schedule_unlock(handler.clone(), channel_id, revert_at);
+    }
+    post_to_modlog(&handler, guild_id, builder.build()).await?;
+
+    response!(ephemeral; handler, inter, "<#{}> is now locked.", channel_id);
+    Ok(())
+}
+
+pub(crate) async fn unlock(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "unlock")) {
+        response!(ephemeral; handler, inter, "You do not have permission to use that command.");
+        return Ok(());
+    }
+
+    let cmd = UnlockCommand::from_interaction(data.into())?;
+    let Some(channel_id) = cmd.channel.or(inter.channel.as_ref().map(|channel| channel.id)) else {
+        response!(ephemeral; handler, inter, "Please specify a channel.");
+        return Ok(());
+    };
+    let reason = cmd.reason.unwrap_or_else(|| "No reason given.".to_owned());
+
+    if unlock_channel(&handler, channel_id, Actor::User(moderator_user), reason).await? {
+        response!(ephemeral; handler, inter, "<#{}> is now unlocked.", channel_id);
+    } else {
+        response!(ephemeral; handler, inter, "<#{}> was not locked with /lock.", channel_id);
+    }
+    Ok(())
+}
+
+/// Restore a locked channel's permissions and log it.  Returns false if the channel wasn't locked.
+async fn unlock_channel(handler: &BotHandler, channel_id: Id<ChannelMarker>, actor: Actor<'_>, reason: String) -> anyhow::Result<bool> {
+    let Some(locked) = LOCKED_CHANNELS.read(&handler.state, |locked| locked.get(&channel_id.get()).cloned()) else {
+        return Ok(false);
+    };
+    let guild_id = Id::new(locked.guild_id);
+    restore_overwrite(handler, guild_id, channel_id, locked.original).await?;
+    LOCKED_CHANNELS.update(&handler.state, |locked| locked.remove(&channel_id.get()));
+
+    actor.log_entry(Some(channel_id), ModLogAction::ChannelUnlock {
+        channel_id: channel_id.get(),
+        reason: reason.clone(),
+    }).log(&handler.state);
+
+    let builder = EmbedBuilder::new()
+        .title("Channel unlocked")
+        .description(reason)
+        .field(EmbedField {name: "Channel".to_string(), value: format!("<#{}>", channel_id), inline: false})
+        .field(EmbedField {name: "Unlocked by".to_string(), value: actor.describe(), inline: false});
+    post_to_modlog(handler, guild_id, builder.build()).await?;
+    Ok(true)
+}
+
+pub(crate) async fn slowmode(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "slowmode")) {
+        response!(ephemeral; handler, inter, "You do not have permission to use that command.");
+        return Ok(());
+    }
+
+    let cmd = SlowmodeCommand::from_interaction(data.into())?;
+    let channel_id = cmd.channel;
+    let seconds = cmd.seconds as u16; // discord caps this at 21600, well within u16
+    let duration = match cmd.duration.as_deref().map(parse_duration) {
+        Some(None) => {
+            response!(ephemeral; handler, inter, "I don't understand that duration.  Try something like 30m, 2h or 1d.");
+            return Ok(());
+        },
+        Some(Some(duration)) => Some(duration),
+        None => None,
+    };
+    let revert_at = match duration.map(|duration| SystemTime::now().checked_add(duration)) {
+        Some(None) => {
+            response!(ephemeral; handler, inter, "That duration is too long.");
+            return Ok(());
+        },
+        Some(Some(revert_at)) => Some(revert_at),
+        None => None,
+    };
+
+    let original = match revert_at {
+        // if there's already a timed slowmode running, go back to what was there before *that*
+        Some(_) => match SLOWMODES.read(&handler.state, |slowmodes| slowmodes.get(&channel_id.get()).map(|change| change.original)) {
+            Some(original) => Some(original),
+            None => Some(handler.client.channel(channel_id).await?.model().await?.rate_limit_per_user.unwrap_or(0)),
+        },
+        None => None,
+    };
+
+    if !actions::execute(&handler, guild_id, None, &Actor::User(moderator_user), Action::Slowmode {channel_id, seconds}).await? {
+        response!(ephemeral; handler, inter, "Shadow mode is on, so slowmode in <#{}> was not changed.  What would have happened has been posted to the modlog channel.", channel_id);
+        return Ok(());
+    }
+    // only now that the change has gone through, so a failed update doesn't leave a revert waiting
+    match (original, revert_at) {
+        (Some(original), Some(revert_at)) => {
+            SLOWMODES.update(&handler.state, |slowmodes| slowmodes.insert(channel_id.get(), SlowmodeChange {guild_id: guild_id.get(), original, revert_at}));
+            schedule_slowmode_revert(handler.clone(), channel_id, revert_at);
+        },
+        _ => {
+            SLOWMODES.update(&handler.state, |slowmodes| slowmodes.remove(&channel_id.get()));
+        },
+    }
+
+    log_slowmode(&handler, guild_id, channel_id, seconds, revert_at, Actor::User(moderator_user)).await?;
+    response!(ephemeral; handler, inter, "Slowmode in <#{}> set to {} seconds.", channel_id, seconds);
+    Ok(())
+}
+
+/// Write a slowmode change that has already been made to the modlog.
+async fn log_slowmode(handler: &BotHandler, guild_id: Id<GuildMarker>, channel_id: Id<ChannelMarker>, seconds: u16, until: Option<SystemTime>, actor: Actor<'_>) -> anyhow::Result<()> {
+    actor.log_entry(Some(channel_id), ModLogAction::Slowmode {
+        channel_id: channel_id.get(),
+        seconds,
+        until,
+    }).log(&handler.state);
+
+    let mut builder = EmbedBuilder::new()
+        .title("Slowmode changed")
+        .field(EmbedField {name: "Channel".to_string(), value: format!("<#{}>", channel_id), inline: false})
+        .field(EmbedField {name: "Seconds between messages".to_string(), value: seconds.to_string(), inline: false})
+        .field(EmbedField {name: "Changed by".to_string(), value: actor.describe(), inline: false});
+    if let Some(until) = until {
+        builder = builder.field(EmbedField {name: "Until".to_string(), value: format!("<t:{}:f>", until.duration_since(UNIX_EPOCH)?.as_secs()), inline: false});
+    }
+    post_to_modlog(handler, guild_id, builder.build()).await?;
+    Ok(())
+}
+
+fn schedule_unlock(handler: Arc<BotHandler>, channel_id: Id<ChannelMarker>, at: SystemTime) {
+    handler.state.clone().spawn_untracked(async move {
+        tokio::time::sleep(at.duration_since(SystemTime::now()).unwrap_or_default()).await;
+        // only unlock if this is still the lock we were scheduled for.  it may have been unlocked
+        // by hand, or unlocked and locked again with a different duration.
+        if LOCKED_CHANNELS.read(&handler.state, |locked| locked.get(&channel_id.get()).map(|lock| lock.revert_at)) != Some(Some(at)) {
+            return;
+        }
+        let res = match bot_user(&handler) {
+            Ok(bot) => unlock_channel(&handler, channel_id, Actor::Bot(bot), "Lock expired.".to_owned()).await.map(|_| ()),
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            tracing::error!("Error automatically unlocking channel {}: {}", channel_id, e);
+        }
+    });
+}
+
+async fn revert_slowmode(handler: &BotHandler, guild_id: Id<GuildMarker>, channel_id: Id<ChannelMarker>, seconds: u16, actor: Actor<'_>) -> anyhow::Result<()> {
+    handler.client.update_channel(channel_id).rate_limit_per_user(seconds)?.await?;
+    log_slowmode(handler, guild_id, channel_id, seconds, None, actor).await
+}
+
+fn schedule_slowmode_revert(handler: Arc<BotHandler>, channel_id: Id<ChannelMarker>, at: SystemTime) {
+    handler.state.clone().spawn_untracked(async move {
+        tokio::time::sleep(at.duration_since(SystemTime::now()).unwrap_or_default()).await;
+        let Some(change) = SLOWMODES.read(&handler.state, |slowmodes| slowmodes.get(&channel_id.get()).cloned()) else {
+            return;
+        };
+        if change.revert_at != at {
+            return;
+        }
+        SLOWMODES.update(&handler.state, |slowmodes| slowmodes.remove(&channel_id.get()));
+        let res = match bot_user(&handler) {
+            Ok(bot) => revert_slowmode(&handler, Id::new(change.guild_id), channel_id, change.original, Actor::Bot(bot)).await,
+            Err(e) => Err(e),
+        };
+        if let Err(e) = res {
+            tracing::error!("Error automatically reverting slowmode in channel {}: {}", channel_id, e);
+        }
+    });
+}
+
+/// Start the timers for any timed locks and slowmodes that were running when the bot last shut
+/// down.  Ones that ran out while we were offline are reverted straight away.
+pub(crate) fn resume_timers(handler: Arc<BotHandler>) {
+    let locks = LOCKED_CHANNELS.read(&handler.state, |locked| locked.iter().filter_map(|(channel_id, lock)| Some((Id::new(*channel_id), lock.revert_at?))).collect::<Vec<_>>());
+    for (channel_id, at) in locks {
+        schedule_unlock(handler.clone(), channel_id, at);
+    }
+    let slowmodes = SLOWMODES.read(&handler.state, |slowmodes| slowmodes.iter().map(|(channel_id, change)| (Id::new(*channel_id), change.revert_at)).collect::<Vec<_>>());
+    for (channel_id, at) in slowmodes {
+        schedule_slowmode_revert(handler.clone(), channel_id, at);
+    }
+}
@@ -1,63 +1,166 @@
 #![feature(never_type)]
+mod actions;
+mod alts;
+mod appeals;
+mod audit_log;
 mod business_logic;
 mod commands;
 mod disk_log;
+mod history;
+mod lockdown;
+mod locks;
+mod metrics;
+mod components;
+mod config_backups;
+mod modmail;
+mod names;
+mod nicknames;
+mod persist;
+mod profiles;
+mod quarantine;
+mod reports;
+mod sanctions;
+mod state;
+mod userinfo;
+#[cfg(test)]
+mod mock_discord;
 
-use std::{io::ErrorKind, sync::{Arc, Mutex}, path::PathBuf};
+use std::{io::ErrorKind, sync::Arc, path::{Path, PathBuf}};
 use std::env::VarError;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
 
-use commands::{ReasonCommand, ChannelCommand, AddModRoleCommand, DeleteModRoleCommand};
+use profiles::Profile;
+use state::{BotHandler, BotState};
+use tokio::task::JoinSet;
+use tracing::Instrument;
+use commands::{ReasonCommand, ChannelCommand, ReportChannelCommand, AppealChannelCommand, AddModRoleCommand, DeleteModRoleCommand, AddModUserCommand, DeleteModUserCommand, ModmailCommand, NoteCommand, HistoryCommand, LockCommand, UnlockCommand, SlowmodeCommand, LockdownCommand, QuarantineCommand, ReleaseCommand, QuarantineRoleCommand, AddSanctionRoleCommand, DeleteSanctionRoleCommand, NicknamePolicyCommand, PermissionsCommand, NativePermissionsCommand, ConfigCommand, ShadowCommand};
 use twilight_http::Client;
 use twilight_interactions::command::CreateCommand;
-use twilight_model::{id::{Id, marker::GuildMarker}, application::command::CommandType};
+use twilight_model::{id::{Id, marker::{GuildMarker, UserMarker}}, application::{command::CommandType, interaction::InteractionType}};
 use twilight_util::builder::command::CommandBuilder;
 
-use twilight_gateway::{Intents, Shard, ShardId, Event};
-use std::io::Write;
+use twilight_gateway::{stream, CloseFrame, Config, Intents, Shard, Event};
+use clap::Parser;
 
+/// Command line options.  Each one can also be set with the environment variable named after it,
+/// including from the .env file.
+#[derive(Parser)]
+#[command(version, about = "Strawberry Moderator, a Discord moderation bot")]
+struct Args {
+    /// Run every bot listed in this profiles file instead of just one.  Each profile has its own
+    /// token, config file and output directory, so --config, --output-dir and AUTHTOKEN are ignored.
+    #[arg(long, env = "BOT_PROFILES")]
+    profiles: Option<PathBuf>,
+    /// Path to the config file.  It's created if it doesn't exist.
+    #[arg(long, env = "CONFIG_FILE", default_value = "config.toml")]
+    config: PathBuf,
+    /// Directory the modlog, transcripts and other saved state are written to.  Defaults to the
+    /// working directory.
+    #[arg(long, env = "OUTPUT_DIR")]
+    output_dir: Option<PathBuf>,
+    /// If set, bot commands will be visible only in this guild.  Leave unset for production use.
+    #[arg(long, env = "DEBUG_GUILD")]
+    debug_guild: Option<Id<GuildMarker>>,
+    /// One of error, warn, info, debug or trace.
+    #[arg(long, env = "LOG_LEVEL", default_value = "info")]
+    log_level: tracing::Level,
+    /// Serve Prometheus metrics on /metrics and gateway health on /healthz at this address, e.g.
+    /// 127.0.0.1:9100.  Off unless set.
+    #[arg(long, env = "METRICS_ADDR")]
+    metrics_addr: Option<SocketAddr>,
+    /// Check the config and the bot token, then exit without registering commands or connecting to
+    /// the gateway.
+    #[arg(long, env = "DRY_RUN")]
+    dry_run: bool,
+}
 
-// If set, bot commands will be visible only in this guild.  Leave at None for production use.
-const DEBUG_GUILD: Option<Id<GuildMarker>> = None;
-//const DEBUG_GUILD: Option<Id<GuildMarker>> = Some(Id::new(/* your numeric server ID here */));
-//const DEBUG_GUILD: Option<Id<GuildMarker>> = Some(Id::new(1191491525432070174));
-
-use twl_fw::{CommandFunc, CommandMap, build_command};
+use twl_fw::{CommandFunc, CommandMap, InteractionHandler, build_command};
 use once_cell::sync::Lazy;
 use phf::phf_map;
 
-static DELETE_MESSAGE_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| business_logic::delete_message(handler, inter, data));
-static PURGE_HOUR_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| business_logic::purge_hour(handler, inter, data));
-static CHANNEL_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| business_logic::channel(handler, inter, data));
-static ADD_MODROLE_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| business_logic::add_modrole(handler, inter, data));
-static DEL_MODROLE_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| business_logic::del_modrole(handler, inter, data));
-static REASON_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| business_logic::reason(handler, inter, data));
+static DELETE_MESSAGE_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| business_logic::delete_message(handler, inter, data)));
+static PURGE_HOUR_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| business_logic::purge_hour(handler, inter, data)));
+static CHANNEL_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| business_logic::channel(handler, inter, data)));
+static ADD_MODROLE_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| business_logic::add_modrole(handler, inter, data)));
+static DEL_MODROLE_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| business_logic::del_modrole(handler, inter, data)));
+static PERMISSIONS_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| business_logic::permissions(handler, inter, data)));
+static NATIVE_PERMISSIONS_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| business_logic::native_permissions(handler, inter, data)));
+static ADD_MODUSER_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| business_logic::add_moduser(handler, inter, data)));
+static DEL_MODUSER_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| business_logic::del_moduser(handler, inter, data)));
+static SHADOW_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| actions::shadow(handler, inter, data)));
+static CONFIG_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| config_backups::config(handler, inter, data)));
+static REASON_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| business_logic::reason(handler, inter, data)));
+static REPORT_CHANNEL_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| business_logic::report_channel(handler, inter, data)));
+static APPEAL_CHANNEL_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| appeals::appeal_channel(handler, inter, data)));
+static NOTE_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| history::note(handler, inter, data)));
+static HISTORY_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| history::history(handler, inter, data)));
+static USER_INFO_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| userinfo::user_info(handler, inter, data)));
+static LOCK_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| locks::lock(handler, inter, data)));
+static UNLOCK_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| locks::unlock(handler, inter, data)));
+static SLOWMODE_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| locks::slowmode(handler, inter, data)));
+static LOCKDOWN_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| lockdown::lockdown(handler, inter, data)));
+static QUARANTINE_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| quarantine::quarantine(handler, inter, data)));
+static RELEASE_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| quarantine::release(handler, inter, data)));
+static QUARANTINE_ROLE_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| quarantine::quarantine_role(handler, inter, data)));
+static ADD_SANCTION_ROLE_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| sanctions::add_sanction_role(handler, inter, data)));
+static DEL_SANCTION_ROLE_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| sanctions::del_sanction_role(handler, inter, data)));
+static NICKNAME_POLICY_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| nicknames::nickname_policy(handler, inter, data)));
+static MODMAIL_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| modmail::modmail(handler, inter, data)));
+static REPORT_MESSAGE_COMMAND: Lazy<CommandFunc> = build_command!(|handler, inter, data| run_command(handler, data.name.clone(), move |handler| reports::report_message(handler, inter, data)));
+
+/// twl_fw calls commands with only its own InteractionHandler, so find the BotHandler wrapping it
+/// and run the command with that, counting it in that bot's metrics.
+async fn run_command<F: Future<Output = anyhow::Result<()>>>(twl: Arc<InteractionHandler>, name: String, command: impl FnOnce(Arc<BotHandler>) -> F) -> anyhow::Result<()> {
+    let handler = BotHandler::find(&twl).ok_or_else(|| anyhow::anyhow!("{} was called by an InteractionHandler that doesn't belong to a bot", name))?;
+    let state = handler.state.clone();
+    metrics::timed(&state, name, command(handler)).await
+}
 
 static COMMAND_MAP: CommandMap = phf_map! {
     "reason" => &REASON_COMMAND,
     "channel" => &CHANNEL_COMMAND,
+    "report_channel" => &REPORT_CHANNEL_COMMAND,
+    "appeal_channel" => &APPEAL_CHANNEL_COMMAND,
+    "modmail" => &MODMAIL_COMMAND,
+    "note" => &NOTE_COMMAND,
+    "history" => &HISTORY_COMMAND,
+    "lock" => &LOCK_COMMAND,
+    "unlock" => &UNLOCK_COMMAND,
+    "slowmode" => &SLOWMODE_COMMAND,
+    "lockdown" => &LOCKDOWN_COMMAND,
+    "quarantine" => &QUARANTINE_COMMAND,
+    "release" => &RELEASE_COMMAND,
+    "quarantine_role" => &QUARANTINE_ROLE_COMMAND,
+    "add_sanction_role" => &ADD_SANCTION_ROLE_COMMAND,
+    "delete_sanction_role" => &DEL_SANCTION_ROLE_COMMAND,
+    "nickname_policy" => &NICKNAME_POLICY_COMMAND,
+    "permissions" => &PERMISSIONS_COMMAND,
+    "native_permissions" => &NATIVE_PERMISSIONS_COMMAND,
+    "config" => &CONFIG_COMMAND,
+    "shadow" => &SHADOW_COMMAND,
     "add_moderator_role" => &ADD_MODROLE_COMMAND,
     "delete_moderator_role" => &DEL_MODROLE_COMMAND,
+    "add_moderator_user" => &ADD_MODUSER_COMMAND,
+    "delete_moderator_user" => &DEL_MODUSER_COMMAND,
     "Delete message" => &DELETE_MESSAGE_COMMAND,
     "Purge last hour" => &PURGE_HOUR_COMMAND,
+    "Report message" => &REPORT_MESSAGE_COMMAND,
+    "User info" => &USER_INFO_COMMAND,
 };
 
-static mut GLOBAL_CONFIG: Option<Mutex<toml_edit::Document>> = None; 
-static mut OUTPUT_PATH: Option<PathBuf> = None;
-
-
-pub(crate) fn get_config() -> &'static Mutex<toml_edit::Document> {
-    unsafe {
-        GLOBAL_CONFIG.as_ref().unwrap()
-    }
-}
-
 // if i had a *lot* more time, i would set something up with inotify to make sure the in memory
 // config and on disk config could never get out of sync.  since i don't, you'll have to just never
 // touch the config file while the bot is running.
 
-pub(crate) fn save_config() {
-    let document = get_config().lock().unwrap();
-    let error_status = std::fs::File::create("config.toml").and_then(|mut file| file.write_all(document.to_string().as_bytes()));
+pub(crate) fn save_config(state: &BotState) {
+    let document = state.config.lock().unwrap();
+    if let Err(e) = config_backups::back_up(state) {
+        tracing::error!("Failed to back up {} before saving it!  Error message was: {}.  Saving anyway.", state.config_path.display(), e);
+    }
+    let error_status = persist::write_atomically(&state.config_path, document.to_string().as_bytes());
     match error_status {
         Ok(()) => {},
         Err(e) => {
@@ -66,106 +169,371 @@ pub(crate) fn save_config() {
     }
 }
 
-pub(crate) fn get_output_path() -> &'static PathBuf {
-    unsafe {OUTPUT_PATH.as_ref().unwrap()}
+/// Write the config out as it is, without making a backup, since it hasn't changed since it was last
+/// saved.  Only used when shutting down, just in case.
+fn flush_config(state: &BotState) {
+    let document = state.config.lock().unwrap();
+    if let Err(e) = persist::write_atomically(&state.config_path, document.to_string().as_bytes()) {
+        tracing::error!("Failed to flush config to disk on shutdown!  Error message was: {}", e);
+    }
+}
+
+/// Whether a user owns the bot's Discord application, or is on the team that does.
+pub(crate) fn is_bot_owner(state: &BotState, user_id: Id<UserMarker>) -> bool {
+    state.owners.get().is_some_and(|owners| owners.contains(&user_id))
 }
 
 #[tokio::main(flavor="current_thread")]
 async fn main() -> Result<!, Box<dyn std::error::Error>> {
+    // read .env before parsing the command line, so its variables work as fallbacks for the flags
+    let dotenv_result = dotenvy::dotenv();
+    let args = Args::parse();
+
     tracing_subscriber::fmt()
-        .with_max_level(tracing::Level::INFO)
+        .with_max_level(args.log_level)
         .init();
 
-    if let Err(e) = dotenvy::dotenv() {
+    if let Err(e) = dotenv_result {
         tracing::error!("Error reading the .env file: {}", e);
     }
 
 
-    let authtoken = std::env::var("AUTHTOKEN").unwrap_or_else(|e| {
-        match e {
-            VarError::NotPresent => eprintln!("Environment variable AUTHTOKEN not set. Cannot sign into Discord.  Exiting."),
-            VarError::NotUnicode(_) => eprintln!("Environment variable AUTHTOKEN is not valid UTF-8.  Exiting."),
+    let profiles = match &args.profiles {
+        Some(path) => profiles::read_profiles(path).unwrap_or_else(|e| {
+            tracing::error!("{}.  Exiting.", e);
+            std::process::exit(1);
+        }),
+        None => {
+            let authtoken = std::env::var("AUTHTOKEN").unwrap_or_else(|e| {
+                match e {
+                    VarError::NotPresent => eprintln!("Environment variable AUTHTOKEN not set. Cannot sign into Discord.  Exiting."),
+                    VarError::NotUnicode(_) => eprintln!("Environment variable AUTHTOKEN is not valid UTF-8.  Exiting."),
+                }
+                std::process::exit(1);
+            });
+            vec![Profile {
+                name: "default".to_owned(),
+                token: authtoken,
+                config: args.config.clone(),
+                output_dir: args.output_dir.clone().unwrap_or_else(||std::env::current_dir().unwrap()),
+            }]
+        },
+    };
+
+    // every bot gets its own state, shard and handler, but they all share this runtime
+    let mut bots = JoinSet::new();
+    let mut states = Vec::new();
+    for profile in profiles {
+        let config = load_config(&profile.config);
+        if let Err(e) = std::fs::create_dir_all(&profile.output_dir) {
+            tracing::error!("Error creating output directory {}: {}.  Exiting.", profile.output_dir.display(), e);
+            std::process::exit(1);
+        }
+        let state = BotState::new(profile.name.clone(), config, profile.config, profile.output_dir);
+        states.push(state.clone());
+        let span = tracing::info_span!("bot", name = %profile.name);
+        let name = profile.name;
+        let (debug_guild, dry_run) = (args.debug_guild, args.dry_run);
+        bots.spawn(async move {
+            (name, run(state, profile.token, debug_guild, dry_run).await)
+        }.instrument(span));
+    }
+
+    if let Some(addr) = args.metrics_addr {
+        // the metrics server doesn't belong to any one bot, so it's a plain task that's handed
+        // every bot's state
+        tokio::spawn(metrics::serve(addr, states.clone()));
+    }
+    tokio::spawn(async move {
+        wait_for_shutdown_signal().await;
+        tracing::info!("Shutting down");
+        for state in states {
+            state.shutdown.send_replace(true);
         }
-        std::process::exit(1);
     });
 
-    let outputdir = std::env::var_os("OUTPUT_DIR").map_or_else(||std::env::current_dir().unwrap(), PathBuf::from);
-    unsafe {
-        OUTPUT_PATH = Some(outputdir);
+    // one bot going down doesn't take the others with it
+    let mut failed = false;
+    while let Some(res) = bots.join_next().await {
+        match res {
+            Ok((_, Ok(()))) => {},
+            Ok((name, Err(e))) => {
+                tracing::error!("Bot {} stopped: {}", name, e);
+                failed = true;
+            },
+            Err(e) => {
+                tracing::error!("A bot crashed: {}", e);
+                failed = true;
+            },
+        }
+    }
+    std::process::exit(if failed {1} else {0});
+}
+
+/// Wait for SIGTERM (what deploys and service managers send) or Ctrl-C.
+async fn wait_for_shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = terminate.recv() => {},
+                    _ = tokio::signal::ctrl_c() => {},
+                }
+                return;
+            },
+            Err(e) => tracing::error!("Can't listen for SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        tracing::error!("Can't listen for Ctrl-C: {}.  The bot can't be shut down cleanly.", e);
+        std::future::pending::<()>().await;
     }
-    
-    let config: toml_edit::Document;
-    match std::fs::read_to_string("config.toml") {
+}
+
+/// Read a bot's config file, or start a new one if it doesn't exist.  Exits if it can't be read.
+fn load_config(path: &Path) -> toml_edit::Document {
+    let config_path = path.display();
+    match std::fs::read_to_string(path) {
         Ok(s) => {
-            config = s.parse()
+            s.parse()
                 .unwrap_or_else(|e| {
-                    tracing::error!("config.toml did not contain valid TOML.  Specific error was: {}.  Exiting.", e);
+                    tracing::error!("{} did not contain valid TOML.  Specific error was: {}.  Exiting.", config_path, e);
                     std::process::exit(1);
-                });
+                })
         },
         Err(e) if e.kind() == ErrorKind::NotFound => {
-            tracing::info!("Config file config.toml not found.  Creating it.");
-            config = toml_edit::Document::default();
+            tracing::info!("Config file {} not found.  Creating it.", config_path);
+            toml_edit::Document::default()
         },
         Err(e) => {
-            tracing::error!("Error reading config.toml: {}.  Exiting.", e);
+            tracing::error!("Error reading {}: {}.  Exiting.", config_path, e);
             std::process::exit(1);
         }
     }
+}
 
-    unsafe {
-        GLOBAL_CONFIG = Some(Mutex::new(config));
-    }
-    
+/// Sign into Discord and run the bot until the gateway connection fails for good.  Returns straight
+/// away on a dry run.
+async fn run(state: Arc<BotState>, authtoken: String, debug_guild: Option<Id<GuildMarker>>, dry_run: bool) -> anyhow::Result<()> {
+    let config_path = state.config_path.display();
     let client = Arc::new(Client::new(authtoken.clone()));
 
     let application = client.current_user_application().await?.model().await?;
     let interaction_client = client.interaction(application.id);
 
+    let owners = match &application.team {
+        Some(team) => team.members.iter().map(|member| member.user.id).collect(),
+        None => application.owner.iter().map(|owner| owner.id).collect(),
+    };
+    let _ = state.owners.set(owners);
+
     // the second str argument is a description, which Discord does not currrently support for
     // message commands (it will error if they aren't blank)
     let commands = [
         ReasonCommand::create_command().into(),
         ChannelCommand::create_command().into(),
+        ReportChannelCommand::create_command().into(),
+        AppealChannelCommand::create_command().into(),
+        ModmailCommand::create_command().into(),
+        NoteCommand::create_command().into(),
+        HistoryCommand::create_command().into(),
+        LockCommand::create_command().into(),
+        UnlockCommand::create_command().into(),
+        SlowmodeCommand::create_command().into(),
+        LockdownCommand::create_command().into(),
+        QuarantineCommand::create_command().into(),
+        ReleaseCommand::create_command().into(),
+        QuarantineRoleCommand::create_command().into(),
+        AddSanctionRoleCommand::create_command().into(),
+        DeleteSanctionRoleCommand::create_command().into(),
+        NicknamePolicyCommand::create_command().into(),
+        PermissionsCommand::create_command().into(),
+        NativePermissionsCommand::create_command().into(),
+        ConfigCommand::create_command().into(),
+        ShadowCommand::create_command().into(),
         AddModRoleCommand::create_command().into(),
         DeleteModRoleCommand::create_command().into(),
+        AddModUserCommand::create_command().into(),
+        DeleteModUserCommand::create_command().into(),
         CommandBuilder::new("Delete message", "", CommandType::Message).build(),
+        CommandBuilder::new("Report message", "", CommandType::Message).build(),
+        CommandBuilder::new("User info", "", CommandType::User).build(),
         //CommandBuilder::new("Purge last hour", "", CommandType::Message).build(), // this is commented out until I can make it do something
     ];
 
-    if let Some(guild_id) = DEBUG_GUILD {
+    if dry_run {
+        let configured_guilds = state.config.lock().unwrap().iter().count();
+        tracing::info!("Dry run: {} is valid and configures {} guilds, and the bot token works for application {}.  Would have registered {} commands{}.  Exiting.",
+            config_path, configured_guilds, application.name, commands.len(),
+            debug_guild.map(|guild_id| format!(" in guild {}", guild_id)).unwrap_or_default());
+        return Ok(());
+    }
+
+    if let Some(guild_id) = debug_guild {
         interaction_client.set_guild_commands(guild_id, &commands).await?;
     } else {
         interaction_client.set_global_commands(&commands).await?;
     }
 
-    let handler = Arc::new(twl_fw::InteractionHandler::new(client.clone(), &COMMAND_MAP));
-    
-    let mut shard = Shard::new(ShardId::ONE, authtoken, Intents::GUILD_MESSAGE_REACTIONS | Intents::DIRECT_MESSAGE_REACTIONS);
+    let handler = BotHandler::new(Arc::new(twl_fw::InteractionHandler::new(client.clone(), &COMMAND_MAP)), state.clone());
+
+    // ask discord how many shards we should have, and run them all side by side.  they all feed the
+    // same handler (and cache), so nothing past this point cares which shard an event came in on.
+    let config = Config::new(authtoken, Intents::GUILDS | Intents::GUILD_MESSAGE_REACTIONS | Intents::DIRECT_MESSAGE_REACTIONS | Intents::GUILD_MESSAGES | Intents::DIRECT_MESSAGES | Intents::MESSAGE_CONTENT | Intents::GUILD_MODERATION | Intents::AUTO_MODERATION_EXECUTION | Intents::GUILD_MEMBERS);
+    let shards = stream::create_recommended(&client, config, |_, builder| builder.build()).await?;
+    tracing::info!("Starting {} shards", shards.len());
+    let tasks = shards.map(|shard| {
+        state.metrics.set_shard_connected(shard.id().number(), false);
+        state.spawn_untracked(run_shard(handler.clone(), shard))
+    }).collect::<Vec<_>>();
+    for task in tasks {
+        // run_shard() only returns once we're shutting down
+        task.await?;
+    }
 
+    // the shards are closed, so nothing new is coming in.  let whatever's still running finish, so
+    // nothing gets done without being logged.
+    let unfinished = state.drain(SHUTDOWN_GRACE_PERIOD).await;
+    if unfinished > 0 {
+        tracing::warn!("Gave up waiting for {} handlers to finish", unfinished);
+    }
+    disk_log::flush(&state);
+    flush_config(&state);
+    tracing::info!("Shut down cleanly");
+    Ok(())
+}
+
+/// How long shutting down waits for handlers that are already running.
+const SHUTDOWN_GRACE_PERIOD: Duration = Duration::from_secs(30);
+
+const MIN_SHARD_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_SHARD_BACKOFF: Duration = Duration::from_secs(600);
+
+/// Receive events from one shard until the bot shuts down.  twilight reconnects and resumes the session by itself
+/// after ordinary disconnects; if the shard fails for good, it's replaced with a fresh one after a
+/// backoff, without touching the other shards.
+async fn run_shard(handler: Arc<BotHandler>, mut shard: Shard) {
+    let state = &handler.state;
+    let mut shutdown = state.shutdown.subscribe();
+    let metrics = &state.metrics;
+    let mut backoff = MIN_SHARD_BACKOFF;
     loop {
-        let event = match shard.next_event().await {
+        let res = tokio::select! {
+            res = shard.next_event() => res,
+            _ = shutdown.wait_for(|stop| *stop) => {
+                if let Err(e) = shard.close(CloseFrame::NORMAL).await {
+                    tracing::warn!("Error closing shard {}: {}", shard.id(), e);
+                    return;
+                }
+                // wait for discord to acknowledge the close.  anything else that comes in
+                // meanwhile is dropped, since we're not taking on new work.
+                let _ = tokio::time::timeout(Duration::from_secs(5), async {
+                    while let Ok(event) = shard.next_event().await {
+                        if matches!(event, Event::GatewayClose(_)) {
+                            break;
+                        }
+                    }
+                }).await;
+                return;
+            },
+        };
+        let event = match res {
             Ok(e) => e,
             Err(e) => {
-                tracing::warn!(?e, "error receiving event");
+                tracing::warn!(?e, shard = %shard.id(), "error receiving event");
                 if e.is_fatal() {
-                    std::process::exit(0);
-                } else {
-                    continue;
+                    metrics.set_shard_connected(shard.id().number(), false);
+                    tracing::error!("Shard {} failed.  Restarting it in {} seconds.", shard.id(), backoff.as_secs());
+                    tokio::select! {
+                        _ = tokio::time::sleep(backoff) => {},
+                        _ = shutdown.wait_for(|stop| *stop) => return,
+                    }
+                    backoff = (backoff * 2).min(MAX_SHARD_BACKOFF);
+                    shard = Shard::with_config(shard.id(), shard.config().clone());
+                    metrics.count_reconnect();
                 }
+                continue;
             }
         };
-        handler.cache().update(&event);
-        match event {
-            Event::InteractionCreate(inter) => {
-                tokio::spawn(handler.clone().handle(inter.0));
+        match &event {
+            // it's connected properly again, so the next failure starts the backoff over
+            Event::Ready(_) => {
+                backoff = MIN_SHARD_BACKOFF;
+                metrics.set_shard_connected(shard.id().number(), true);
             },
-            Event::Ready(ready) => {
-                let config = get_config().lock().unwrap();
-                tracing::info!("bot is in {} guilds, of which {} are configured", ready.guilds.len(), ready.guilds.iter().filter(|x| config.contains_key(x.id.to_string().as_str())).count());
-                tracing::info!("Strawberry Moderator reporting for duty!");
+            Event::Resumed => metrics.set_shard_connected(shard.id().number(), true),
+            // twilight reconnects by itself after these
+            Event::GatewayClose(_) => {
+                metrics.set_shard_connected(shard.id().number(), false);
+                metrics.count_reconnect();
             },
             _ => {},
         }
+        dispatch(&handler, event);
+    }
+}
+
+/// Hand one gateway event to whatever deals with it.  Anything slow is spawned, so this returns
+/// straight away.
+fn dispatch(handler: &Arc<BotHandler>, event: Event) {
+    let state = &handler.state;
+    handler.cache().update(&event);
+    match event {
+        Event::InteractionCreate(inter) => match inter.kind {
+            InteractionType::MessageComponent | InteractionType::ModalSubmit => {
+                state.spawn(components::handle(handler.clone(), inter.0));
+            },
+            _ => {
+                state.spawn(handler.twl().handle(inter.0));
+            },
+        },
+        Event::MessageCreate(message) => {
+            state.spawn(modmail::on_message(handler.clone(), message.0));
+        },
+        Event::BanAdd(ban) => {
+            state.spawn(alts::on_ban(state.clone(), ban.clone()));
+            state.spawn(appeals::on_ban(handler.clone(), ban));
+        },
+        Event::BanRemove(unban) => {
+            state.spawn(alts::on_unban(state.clone(), unban));
+        },
+        Event::AutoModerationActionExecution(action) => {
+            userinfo::on_automod_action(state, &action);
+        },
+        Event::GuildAuditLogEntryCreate(entry) => {
+            state.spawn(audit_log::on_audit_log_entry(handler.clone(), entry.0));
+        },
+        Event::MemberAdd(add) => {
+            state.spawn(alts::on_member_add(handler.clone(), (*add).clone()));
+            state.spawn(nicknames::on_member_add(handler.clone(), (*add).clone()));
+            state.spawn(sanctions::on_member_add(handler.clone(), *add));
+        },
+        Event::GuildCreate(guild) => {
+            let bot = state.clone();
+            state.spawn(async move { sanctions::on_members(&bot, guild.id, &guild.members) });
+        },
+        Event::MemberChunk(chunk) => {
+            let bot = state.clone();
+            state.spawn(async move { sanctions::on_members(&bot, chunk.guild_id, &chunk.members) });
+        },
+        Event::MemberUpdate(update) => {
+            sanctions::on_member_update(state, &update);
+            state.spawn(nicknames::on_member_update(handler.clone(), *update));
+        },
+        Event::Ready(ready) => {
+            // every shard gets its own READY, and gets another one whenever it's restarted, but
+            // the timers should only be picked back up once
+            if !state.resumed.swap(true, Ordering::SeqCst) {
+                locks::resume_timers(handler.clone());
+                lockdown::resume(handler.clone());
+            }
+            let config = state.config.lock().unwrap();
+            let shard = ready.shard.map_or_else(|| "0".to_owned(), |shard| shard.number().to_string());
+            tracing::info!("shard {} is in {} guilds, of which {} are configured", shard, ready.guilds.len(), ready.guilds.iter().filter(|x| config.contains_key(x.id.to_string().as_str())).count());
+            tracing::info!("Strawberry Moderator reporting for duty!");
+        },
+        _ => {},
     }
 }
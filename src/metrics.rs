@@ -0,0 +1,200 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+use crate::state::BotState;
+
+// counters for the optional metrics endpoint (--metrics-addr).  /metrics is in the prometheus text
+// format, and /healthz says whether every shard of every bot is connected to the gateway, which is
+// the thing to alert on if the bot quietly dies.
+
+#[derive(Default)]
+struct CommandStats {
+    calls: u64,
+    errors: u64,
+    seconds: f64,
+}
+
+/// One bot's metrics.  Lives in its BotState.
+#[derive(Default)]
+pub(crate) struct Metrics {
+    /// Slash commands, context menu commands and components, by name
+    commands: Mutex<BTreeMap<String, CommandStats>>,
+    /// Modlog entries written, by ModLogAction variant
+    actions: Mutex<BTreeMap<String, u64>>,
+    reconnects: AtomicU64,
+    /// Modlog posts sent to Discord that haven't been answered yet
+    modlog_posts_pending: AtomicU64,
+    /// Shard number -> whether it's connected right now
+    shards: Mutex<BTreeMap<u64, bool>>,
+}
+
+impl Metrics {
+    fn record_command(&self, name: &str, elapsed: Duration, failed: bool) {
+        let mut commands = self.commands.lock().unwrap();
+        let stats = commands.entry(name.to_owned()).or_default();
+        stats.calls += 1;
+        stats.errors += failed as u64;
+        stats.seconds += elapsed.as_secs_f64();
+    }
+
+    pub(crate) fn count_action(&self, action: &str) {
+        *self.actions.lock().unwrap().entry(action.to_owned()).or_default() += 1;
+    }
+
+    pub(crate) fn count_reconnect(&self) {
+        self.reconnects.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn set_shard_connected(&self, shard: u64, connected: bool) {
+        self.shards.lock().unwrap().insert(shard, connected);
+    }
+
+    /// Count a modlog post as pending until the returned guard is dropped.  Hold it across the
+    /// request, so posts stuck behind Discord's rate limits show up here.
+    pub(crate) fn modlog_post(&self) -> PendingModlogPost<'_> {
+        self.modlog_posts_pending.fetch_add(1, Ordering::Relaxed);
+        PendingModlogPost(self)
+    }
+}
+
+pub(crate) struct PendingModlogPost<'a>(&'a Metrics);
+
+impl Drop for PendingModlogPost<'_> {
+    fn drop(&mut self) {
+        self.0.modlog_posts_pending.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Run a command handler, counting it (and whether it failed, and how long it took) under `name`.
+pub(crate) async fn timed<T>(state: &BotState, name: String, future: impl Future<Output = anyhow::Result<T>>) -> anyhow::Result<T> {
+    let start = Instant::now();
+    let res = future.await;
+    state.metrics.record_command(&name, start.elapsed(), res.is_err());
+    res
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn render(bots: &[Arc<BotState>]) -> String {
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &mut dyn Iterator<Item = (String, String)>| {
+        let _ = writeln!(out, "# HELP {} {}\n# TYPE {} {}", name, help, name, kind);
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+        }
+    };
+
+    let commands = bots.iter().map(|bot| (escape(&bot.name), bot.metrics.commands.lock().unwrap().iter()
+            .map(|(command, stats)| (escape(command), stats.calls, stats.errors, stats.seconds))
+            .collect::<Vec<_>>()))
+        .collect::<Vec<_>>();
+    let command_samples = |value: fn(&(String, u64, u64, f64)) -> String| commands.iter()
+        .flat_map(move |(bot, commands)| commands.iter().map(move |stats| (format!("bot=\"{}\",command=\"{}\"", bot, stats.0), value(stats))));
+    metric("smb_commands_total", "counter", "Commands and components handled.", &mut command_samples(|stats| stats.1.to_string()));
+    metric("smb_command_errors_total", "counter", "Commands and components that returned an error.", &mut command_samples(|stats| stats.2.to_string()));
+    metric("smb_command_seconds_total", "counter", "Time spent handling each command.  Divide by smb_commands_total for the average.", &mut command_samples(|stats| stats.3.to_string()));
+
+    metric("smb_modlog_actions_total", "counter", "Modlog entries written, by action.", &mut bots.iter().flat_map(|bot| {
+        let name = escape(&bot.name);
+        bot.metrics.actions.lock().unwrap().iter()
+            .map(|(action, count)| (format!("bot=\"{}\",action=\"{}\"", name, escape(action)), count.to_string()))
+            .collect::<Vec<_>>()
+    }));
+    metric("smb_gateway_reconnects_total", "counter", "Times a shard reconnected or was restarted.", &mut bots.iter()
+        .map(|bot| (format!("bot=\"{}\"", escape(&bot.name)), bot.metrics.reconnects.load(Ordering::Relaxed).to_string())));
+    metric("smb_gateway_shard_connected", "gauge", "Whether each shard is connected to the gateway.", &mut bots.iter().flat_map(|bot| {
+        let name = escape(&bot.name);
+        bot.metrics.shards.lock().unwrap().iter()
+            .map(|(shard, connected)| (format!("bot=\"{}\",shard=\"{}\"", name, shard), (*connected as u8).to_string()))
+            .collect::<Vec<_>>()
+    }));
+    metric("smb_modlog_posts_pending", "gauge", "Modlog posts waiting on Discord, including any held up by rate limits.", &mut bots.iter()
+        .map(|bot| (format!("bot=\"{}\"", escape(&bot.name)), bot.metrics.modlog_posts_pending.load(Ordering::Relaxed).to_string())));
+    metric("smb_cache_entries", "gauge", "Entries in the gateway cache.", &mut bots.iter().flat_map(|bot| {
+        let name = escape(&bot.name);
+        let Some(handler) = bot.handler.get() else {
+            return Vec::new();
+        };
+        let stats = handler.cache().stats();
+        [("guilds", stats.guilds()), ("channels", stats.channels()), ("roles", stats.roles()), ("members", stats.members()), ("users", stats.users())]
+            .into_iter()
+            .map(|(kind, count)| (format!("bot=\"{}\",kind=\"{}\"", name, kind), count.to_string()))
+            .collect::<Vec<_>>()
+    }));
+    out
+}
+
+/// Whether everything is connected, and a line per shard saying what isn't.
+fn health(bots: &[Arc<BotState>]) -> (bool, String) {
+    let mut healthy = true;
+    let mut out = String::new();
+    for bot in bots {
+        let shards = bot.metrics.shards.lock().unwrap();
+        if shards.is_empty() {
+            healthy = false;
+            let _ = writeln!(out, "{}: no shards running", bot.name);
+        }
+        for (shard, connected) in shards.iter() {
+            healthy &= *connected;
+            let _ = writeln!(out, "{} shard {}: {}", bot.name, shard, if *connected {"connected"} else {"disconnected"});
+        }
+    }
+    (healthy, out)
+}
+
+/// Serve /metrics and /healthz for these bots until the process exits.
+pub(crate) async fn serve(addr: SocketAddr, bots: Vec<Arc<BotState>>) {
+    let listener = match TcpListener::bind(addr).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            tracing::error!("Can't listen for metrics on {}: {}.  Carrying on without them.", addr, e);
+            return;
+        }
+    };
+    tracing::info!("Serving metrics on http://{}/metrics", addr);
+    let bots: Arc<[Arc<BotState>]> = bots.into();
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                // not BotState::spawn: like serve() itself, this doesn't belong to any one bot
+                tokio::spawn(respond(stream, bots.clone()));
+            },
+            Err(e) => tracing::warn!("Error accepting metrics connection: {}", e),
+        }
+    }
+}
+
+/// How long a client gets to send its request line, and how long that line can be, so that a slow
+/// or misbehaving one can't hold a task and an ever-growing buffer forever.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_REQUEST_LINE: u64 = 8192;
+
+async fn respond(mut stream: TcpStream, bots: Arc<[Arc<BotState>]>) {
+    let mut request_line = String::new();
+    let mut reader = BufReader::new((&mut stream).take(MAX_REQUEST_LINE));
+    match tokio::time::timeout(REQUEST_TIMEOUT, reader.read_line(&mut request_line)).await {
+        Ok(Ok(_)) => (),
+        _ => return,
+    }
+    let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+    let (status, content_type, body) = match path.split('?').next().unwrap_or_default() {
+        "/metrics" => ("200 OK", "text/plain; version=0.0.4", render(&bots)),
+        "/healthz" => match health(&bots) {
+            (true, details) => ("200 OK", "text/plain", details),
+            (false, details) => ("503 Service Unavailable", "text/plain", details),
+        },
+        _ => ("404 Not Found", "text/plain", "Try /metrics or /healthz\n".to_owned()),
+    };
+    let response = format!("HTTP/1.1 {}\r\ncontent-type: {}\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}", status, content_type, body.len(), body);
+    let _ = stream.write_all(response.as_bytes()).await;
+}
@@ -0,0 +1,448 @@
+// a fake Discord for the tests to run against.  FakeDiscord is a tiny HTTP server that the twilight
+// client gets pointed at instead of discord.com: it records every request the bot makes and
+// answers with whatever the test stubbed in (or an empty success).  the "gateway" is just
+// TestEnv::play(), which feeds events through the same dispatch() the real shard loop uses.
+//
+// every TestEnv is a separate bot with its own BotState and its own scratch output directory (gone
+// again once the TestEnv is dropped), so tests can run side by side without seeing each other's
+// config, modlog or persisted state.
+
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use twilight_gateway::Event;
+use twilight_http::Client;
+use twilight_model::gateway::payload::incoming::{InteractionCreate, Ready};
+use twl_fw::InteractionHandler;
+
+use crate::state::{BotHandler, BotState};
+use crate::{dispatch, COMMAND_MAP};
+
+pub(crate) const GUILD_ID: u64 = 3000;
+pub(crate) const CHANNEL_ID: u64 = 4000;
+pub(crate) const MODERATOR_ID: u64 = 5000;
+pub(crate) const BOT_ID: u64 = 9000;
+
+#[derive(Clone, Debug)]
+pub(crate) struct RecordedRequest {
+    pub(crate) method: String,
+    /// Path with the /api/v10 prefix taken off, e.g. /channels/4000/messages
+    pub(crate) path: String,
+    /// The JSON body, or Null if there wasn't one (or it wasn't JSON)
+    pub(crate) body: Value,
+}
+
+struct Stub {
+    method: String,
+    path: String,
+    status: u16,
+    body: Value,
+}
+
+#[derive(Default)]
+struct FakeState {
+    requests: Vec<RecordedRequest>,
+    stubs: Vec<Stub>,
+}
+
+pub(crate) struct FakeDiscord {
+    addr: String,
+    state: Arc<Mutex<FakeState>>,
+}
+
+impl FakeDiscord {
+    pub(crate) async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let state = Arc::new(Mutex::new(FakeState::default()));
+        let accept_state = state.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, accept_state.clone()));
+            }
+        });
+        Self {addr, state}
+    }
+
+    /// A client that sends everything here instead of to Discord.
+    pub(crate) fn client(&self) -> Client {
+        Client::builder()
+            .token("Bot fake-token".to_owned())
+            .proxy(self.addr.clone(), true)
+            .ratelimiter(None)
+            .build()
+    }
+
+    /// Answer requests to exactly this method and path with `body` from now on.
+    pub(crate) fn stub(&self, method: &str, path: &str, status: u16, body: Value) {
+        self.state.lock().unwrap().stubs.push(Stub {method: method.to_owned(), path: path.to_owned(), status, body});
+    }
+
+    pub(crate) fn requests(&self) -> Vec<RecordedRequest> {
+        self.state.lock().unwrap().requests.clone()
+    }
+
+    /// Wait for the bot to make a request to this method and path, and return it.  Panics after 5
+    /// seconds, listing what the bot did do.
+    pub(crate) async fn wait_for(&self, method: &str, path: &str) -> RecordedRequest {
+        for _ in 0..500 {
+            if let Some(request) = self.requests().into_iter().find(|r| r.method == method && r.path == path) {
+                return request;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        let seen = self.requests().iter().map(|r| format!("{} {}", r.method, r.path)).collect::<Vec<_>>();
+        panic!("bot never made a {} {} request.  it made: {:#?}", method, path, seen);
+    }
+
+    /// Every embed the bot has posted to a channel, in order.
+    pub(crate) fn embeds_posted_to(&self, channel_id: u64) -> Vec<Value> {
+        let path = format!("/channels/{}/messages", channel_id);
+        self.requests().into_iter()
+            .filter(|r| r.method == "POST" && r.path == path)
+            .flat_map(|r| r.body["embeds"].as_array().cloned().unwrap_or_default())
+            .collect()
+    }
+}
+
+async fn serve(stream: TcpStream, state: Arc<Mutex<FakeState>>) {
+    let mut stream = BufReader::new(stream);
+    // keep-alive: one connection can carry any number of requests
+    loop {
+        let mut request_line = String::new();
+        if stream.read_line(&mut request_line).await.unwrap_or(0) == 0 {
+            return;
+        }
+        let mut parts = request_line.split_whitespace();
+        let method = parts.next().unwrap_or_default().to_owned();
+        let target = parts.next().unwrap_or_default();
+        // proxied requests can come in absolute form
+        let target = target.find("/api/").map_or(target, |idx| &target[idx..]);
+        let path = target.split('?').next().unwrap_or_default();
+        let path = path.strip_prefix("/api/v10").unwrap_or(path).to_owned();
+
+        let mut content_length = 0;
+        loop {
+            let mut header = String::new();
+            if stream.read_line(&mut header).await.unwrap_or(0) == 0 {
+                return;
+            }
+            let header = header.trim_end();
+            if header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.eq_ignore_ascii_case("content-length") {
+                    content_length = value.trim().parse().unwrap_or(0);
+                }
+            }
+        }
+        let mut body = vec![0; content_length];
+        if stream.read_exact(&mut body).await.is_err() {
+            return;
+        }
+        let body = serde_json::from_slice(&body).unwrap_or(Value::Null);
+
+        let (status, response) = {
+            let mut state = state.lock().unwrap();
+            let response = state.stubs.iter()
+                .find(|stub| stub.method == method && stub.path == path)
+                .map_or((200, json!({})), |stub| (stub.status, stub.body.clone()));
+            state.requests.push(RecordedRequest {method, path, body});
+            response
+        };
+        let response = response.to_string();
+        let head = format!("HTTP/1.1 {} Fake\r\ncontent-type: application/json\r\ncontent-length: {}\r\n\r\n", status, response.len());
+        if stream.get_mut().write_all((head + &response).as_bytes()).await.is_err() {
+            return;
+        }
+    }
+}
+
+/// One test's view of the bot: a fake Discord, a handler talking to it, and the bot's own state.
+pub(crate) struct TestEnv {
+    pub(crate) discord: FakeDiscord,
+    pub(crate) handler: Arc<BotHandler>,
+    pub(crate) state: Arc<BotState>,
+}
+
+impl TestEnv {
+    /// `config` is the whole config file, as TOML.
+    pub(crate) async fn new(config: &str) -> Self {
+        static COUNT: AtomicUsize = AtomicUsize::new(0);
+        let dir = std::env::temp_dir().join(format!("strawberry-mod-bot-test-{}-{}", std::process::id(), COUNT.fetch_add(1, Ordering::Relaxed)));
+        std::fs::create_dir_all(&dir).unwrap();
+        let config = config.parse().expect("test config is not valid TOML");
+        let state = BotState::new("test".to_owned(), config, dir.join("config.toml"), dir);
+        let _ = state.owners.set(vec![]);
+
+        let discord = FakeDiscord::start().await;
+        let handler = BotHandler::new(Arc::new(InteractionHandler::new(Arc::new(discord.client()), &COMMAND_MAP)), state.clone());
+        Self {discord, handler, state}
+    }
+
+    /// Feed gateway events to the bot as if they came from the shard.
+    pub(crate) async fn play(&self, events: impl IntoIterator<Item = Event>) {
+        for event in events {
+            dispatch(&self.handler, event);
+            tokio::task::yield_now().await;
+        }
+    }
+
+    /// What this bot has written to modlog.ndjson.
+    pub(crate) fn modlog_entries(&self) -> Vec<Value> {
+        std::fs::read_to_string(self.state.output_path.join("modlog.ndjson")).unwrap_or_default()
+            .lines()
+            .map(|line| serde_json::from_str(line).expect("modlog.ndjson line is not JSON"))
+            .collect()
+    }
+}
+
+impl Drop for TestEnv {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.state.output_path);
+    }
+}
+
+pub(crate) fn user(id: u64, name: &str) -> Value {
+    json!({"id": id.to_string(), "username": name, "discriminator": "0", "avatar": null})
+}
+
+/// A moderator, as they appear on an interaction.  `permissions` is the Discord permission bitset.
+pub(crate) fn member(user: Value, permissions: u64) -> Value {
+    json!({
+        "user": user,
+        "roles": [],
+        "joined_at": "2020-01-01T00:00:00.000000+00:00",
+        "deaf": false,
+        "mute": false,
+        "flags": 0,
+        "permissions": permissions.to_string(),
+    })
+}
+
+pub(crate) fn message(id: u64, channel_id: u64, author: Value, content: &str) -> Value {
+    json!({
+        "id": id.to_string(),
+        "channel_id": channel_id.to_string(),
+        "guild_id": GUILD_ID.to_string(),
+        "author": author,
+        "content": content,
+        "timestamp": "2024-01-01T00:00:00.000000+00:00",
+        "edited_timestamp": null,
+        "tts": false,
+        "mention_everyone": false,
+        "mentions": [],
+        "mention_roles": [],
+        "attachments": [],
+        "embeds": [],
+        "components": [],
+        "pinned": false,
+        "type": 0,
+    })
+}
+
+/// An application command interaction sent by `member` in CHANNEL_ID.  `data` is the command's
+/// data object, including its name and type.
+pub(crate) fn command(id: u64, member: Value, data: Value) -> Event {
+    let interaction = json!({
+        "id": id.to_string(),
+        "application_id": "2000",
+        "type": 2,
+        "token": format!("token-{}", id),
+        "version": 1,
+        "guild_id": GUILD_ID.to_string(),
+        "channel_id": CHANNEL_ID.to_string(),
+        "channel": {"id": CHANNEL_ID.to_string(), "type": 0, "name": "general"},
+        "member": member,
+        "app_permissions": "8",
+        "locale": "en-US",
+        "data": data,
+    });
+    Event::InteractionCreate(Box::new(InteractionCreate(serde_json::from_value(interaction).expect("bad interaction JSON"))))
+}
+
+/// Path of the callback the bot answers interaction `id` on.
+pub(crate) fn callback_path(id: u64) -> String {
+    format!("/interactions/{}/token-{}/callback", id, id)
+}
+
+/// READY, so the bot knows who it is.
+pub(crate) fn ready() -> Event {
+    let ready = json!({
+        "v": 10,
+        "user": {"id": BOT_ID.to_string(), "username": "strawberry", "discriminator": "0", "avatar": null, "bot": true, "mfa_enabled": false},
+        "guilds": [{"id": GUILD_ID.to_string(), "unavailable": true}],
+        "session_id": "fake-session",
+        "resume_gateway_url": "wss://gateway.invalid",
+        "shard": [0, 1],
+        "application": {"id": "2000", "flags": 0},
+    });
+    Event::Ready(Box::new(serde_json::from_value::<Ready>(ready).expect("bad READY JSON")))
+}
+
+#[tokio::test]
+async fn channel_command_sets_modlog_channel() {
+    let env = TestEnv::new("").await;
+    let moderator = member(user(MODERATOR_ID, "moderator"), 8);
+    env.play([command(1001, moderator, json!({
+        "id": "6000",
+        "name": "channel",
+        "type": 1,
+        "options": [{"name": "channel", "type": 7, "value": "7000"}],
+    }))]).await;
+
+    env.discord.wait_for("POST", &callback_path(1001)).await;
+    let modlog_channel = env.state.config.lock().unwrap()[GUILD_ID.to_string().as_str()]["modlog_channel_id"].as_integer();
+    assert_eq!(modlog_channel, Some(7000));
+
+    let embeds = env.discord.embeds_posted_to(7000);
+    assert_eq!(embeds.len(), 1);
+    assert_eq!(embeds[0]["title"], "Configuration changed");
+    assert!(env.modlog_entries().iter().any(|entry| entry.to_string().contains("modlog_channel_id")));
+}
+
+#[tokio::test]
+async fn delete_message_deletes_and_logs() {
+    let env = TestEnv::new(&format!("[\"{}\"]\nmodlog_channel_id = 7100\n", GUILD_ID)).await;
+    let moderator = member(user(MODERATOR_ID, "moderator"), 8);
+    let offending = message(8000, CHANNEL_ID, user(5100, "spammer"), "buy my stuff");
+    env.play([ready(), command(1002, moderator, json!({
+        "id": "6001",
+        "name": "Delete message",
+        "type": 3,
+        "target_id": "8000",
+        "resolved": {"messages": {"8000": offending}},
+    }))]).await;
+
+    env.discord.wait_for("DELETE", &format!("/channels/{}/messages/8000", CHANNEL_ID)).await;
+    let callback = env.discord.wait_for("POST", &callback_path(1002)).await;
+    assert_eq!(callback.body["data"]["content"], "Message deleted");
+
+    let embeds = env.discord.embeds_posted_to(7100);
+    assert_eq!(embeds.len(), 1);
+    assert_eq!(embeds[0]["title"], "Message removed by moderator");
+    assert_eq!(embeds[0]["description"], "buy my stuff");
+    assert_eq!(env.modlog_entries().len(), 1);
+}
+
+#[tokio::test]
+async fn delete_message_in_shadow_mode_only_simulates() {
+    let env = TestEnv::new(&format!("[\"{}\"]\nmodlog_channel_id = 7200\nshadow_mode = true\n", GUILD_ID)).await;
+    let moderator = member(user(MODERATOR_ID, "moderator"), 8);
+    let offending = message(8001, CHANNEL_ID, user(5101, "spammer"), "buy my stuff");
+    env.play([command(1003, moderator, json!({
+        "id": "6001",
+        "name": "Delete message",
+        "type": 3,
+        "target_id": "8001",
+        "resolved": {"messages": {"8001": offending}},
+    }))]).await;
+
+    env.discord.wait_for("POST", &callback_path(1003)).await;
+    assert!(!env.discord.requests().iter().any(|r| r.method == "DELETE"));
+    let embeds = env.discord.embeds_posted_to(7200);
+    assert_eq!(embeds.len(), 1);
+    assert_eq!(embeds[0]["title"], "Simulated action");
+    assert!(env.modlog_entries().is_empty());
+}
+
+#[tokio::test]
+async fn delete_message_without_permission_is_refused() {
+    let env = TestEnv::new(&format!("[\"{}\"]\nmodlog_channel_id = 7300\n", GUILD_ID)).await;
+    let bystander = member(user(5200, "bystander"), 0);
+    let offending = message(8002, CHANNEL_ID, user(5102, "spammer"), "buy my stuff");
+    env.play([command(1004, bystander, json!({
+        "id": "6001",
+        "name": "Delete message",
+        "type": 3,
+        "target_id": "8002",
+        "resolved": {"messages": {"8002": offending}},
+    }))]).await;
+
+    let callback = env.discord.wait_for("POST", &callback_path(1004)).await;
+    assert_eq!(callback.body["data"]["content"], "You do not have permission to use that command.");
+    assert!(!env.discord.requests().iter().any(|r| r.method == "DELETE"));
+    assert!(env.discord.embeds_posted_to(7300).is_empty());
+    assert!(env.modlog_entries().is_empty());
+}
+
+#[tokio::test]
+async fn settings_need_manage_server() {
+    let env = TestEnv::new("").await;
+    let moderator = member(user(MODERATOR_ID, "moderator"), 1 << 13); // Manage Messages only
+    env.play([command(1005, moderator, json!({
+        "id": "6002",
+        "name": "report_channel",
+        "type": 1,
+        "options": [{"name": "channel", "type": 7, "value": "7400"}],
+    }))]).await;
+
+    let callback = env.discord.wait_for("POST", &callback_path(1005)).await;
+    assert_eq!(callback.body["data"]["content"], "You need the Manage Server permission to change the bot's settings.");
+    assert!(env.state.config.lock().unwrap().get(GUILD_ID.to_string().as_str()).is_none());
+    assert!(env.modlog_entries().is_empty());
+}
+
+#[tokio::test]
+async fn channel_command_needs_manage_server() {
+    let env = TestEnv::new(&format!("[\"{}\"]\nmodlog_channel_id = 7500\n", GUILD_ID)).await;
+    let moderator = member(user(MODERATOR_ID, "moderator"), 1 << 13); // Manage Messages only
+    env.play([command(1006, moderator, json!({
+        "id": "6000",
+        "name": "channel",
+        "type": 1,
+        "options": [{"name": "channel", "type": 7, "value": "7501"}],
+    }))]).await;
+
+    let callback = env.discord.wait_for("POST", &callback_path(1006)).await;
+    assert_eq!(callback.body["data"]["content"], "You need the Manage Server permission to change the bot's settings.");
+    let modlog_channel = env.state.config.lock().unwrap()[GUILD_ID.to_string().as_str()]["modlog_channel_id"].as_integer();
+    assert_eq!(modlog_channel, Some(7500));
+    assert!(env.discord.embeds_posted_to(7501).is_empty());
+    assert!(env.modlog_entries().is_empty());
+}
+
+#[tokio::test]
+async fn empty_moderator_lists_leave_native_permissions_on() {
+    // what deleting a moderator role leaves behind in a guild that never had one
+    let env = TestEnv::new(&format!("[\"{}\"]\nmodlog_channel_id = 7600\nmoderator_roles = []\n", GUILD_ID)).await;
+    let moderator = member(user(MODERATOR_ID, "moderator"), 1 << 13); // Manage Messages only
+    let offending = message(8003, CHANNEL_ID, user(5103, "spammer"), "buy my stuff");
+    env.play([ready(), command(1007, moderator, json!({
+        "id": "6001",
+        "name": "Delete message",
+        "type": 3,
+        "target_id": "8003",
+        "resolved": {"messages": {"8003": offending}},
+    }))]).await;
+
+    env.discord.wait_for("DELETE", &format!("/channels/{}/messages/8003", CHANNEL_ID)).await;
+    let callback = env.discord.wait_for("POST", &callback_path(1007)).await;
+    assert_eq!(callback.body["data"]["content"], "Message deleted");
+}
+
+#[tokio::test]
+async fn lock_in_shadow_mode_only_simulates() {
+    let env = TestEnv::new(&format!("[\"{}\"]\nmodlog_channel_id = 7700\nshadow_mode = true\n", GUILD_ID)).await;
+    env.discord.stub("GET", "/channels/7701", 200, json!({"id": "7701", "type": 0, "guild_id": GUILD_ID.to_string(), "permission_overwrites": []}));
+    let moderator = member(user(MODERATOR_ID, "moderator"), 8);
+    env.play([command(1008, moderator, json!({
+        "id": "6003",
+        "name": "lock",
+        "type": 1,
+        "options": [{"name": "channel", "type": 7, "value": "7701"}],
+    }))]).await;
+
+    let callback = env.discord.wait_for("POST", &callback_path(1008)).await;
+    assert_eq!(callback.body["data"]["content"], "Shadow mode is on, so <#7701> was not locked.  What would have happened has been posted to the modlog channel.");
+    assert!(!env.discord.requests().iter().any(|r| r.method == "PUT" && r.path.starts_with("/channels/7701/permissions")));
+    let embeds = env.discord.embeds_posted_to(7700);
+    assert_eq!(embeds.len(), 1);
+    assert_eq!(embeds[0]["title"], "Simulated action");
+    assert!(env.modlog_entries().is_empty());
+}
@@ -0,0 +1,310 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Deserialize};
+use twilight_interactions::command::CommandModel;
+use twilight_model::application::interaction::{application_command::CommandData, Interaction};
+use twilight_model::channel::Message;
+use twilight_model::channel::message::component::{ActionRow, Button, ButtonStyle, Component};
+use twilight_model::channel::message::embed::EmbedField;
+use twilight_model::http::attachment::Attachment;
+use twilight_model::id::Id;
+use twilight_model::id::marker::{ChannelMarker, GuildMarker};
+use twilight_model::user::User;
+use twilight_util::builder::embed::EmbedBuilder;
+use twl_fw::response;
+
+use anyhow::anyhow;
+
+use smb_log_format::{ModLogEntry, ModLogAction};
+use crate::business_logic::{format_user, guild_name, get_config_channel, get_guild, get_initiating_user, get_modlog_channel, is_allowed_to_use, is_server_admin, update_config_by};
+use crate::appeals;
+use crate::commands::ModmailCommand;
+use crate::disk_log::{download_file, ModLogEntryExt};
+use crate::persist::Persisted;
+use crate::state::{BotHandler, BotState};
+
+/// Open tickets, keyed by the ID of the user who opened them.
+static TICKETS: Persisted<HashMap<u64, Ticket>> = Persisted::new("modmail_tickets.msgpack");
+
+/// Discord refuses messages longer than this.
+const MAX_MESSAGE_LENGTH: usize = 2000;
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Ticket {
+    guild_id: u64,
+    thread_id: u64,
+    dm_channel_id: u64,
+    opened: SystemTime,
+}
+
+/// Called for every MESSAGE_CREATE the bot sees.  DMs get relayed into the sender's ticket thread
+/// (opening one if necessary) and messages in ticket threads get relayed back to the user.
+pub(crate) async fn on_message(handler: Arc<BotHandler>, message: Message) {
+    if message.author.bot {
+        return;
+    }
+    let res = if message.guild_id.is_none() {
+        on_direct_message(&handler, &message).await
+    } else {
+        on_thread_message(&handler, &message).await
+    };
+    if let Err(e) = res {
+        tracing::error!("Error relaying modmail message {}: {}", message.id, e);
+    }
+}
+
+async fn on_direct_message(handler: &BotHandler, message: &Message) -> anyhow::Result<()> {
+    let ticket = match TICKETS.read(&handler.state, |tickets| tickets.get(&message.author.id.get()).cloned()) {
+        Some(ticket) => ticket,
+        None => {
+            if appeals::on_direct_message(handler, message).await? {
+                return Ok(());
+            }
+            let mut guilds = Vec::new();
+            for guild_id in configured_modmail_guilds(&handler.state) {
+                if handler.client.guild_member(guild_id, message.author.id).await.is_ok() {
+                    guilds.push(guild_id);
+                }
+            }
+            match guilds[..] {
+                [] => {
+                    handler.client.create_message(message.channel_id).content("You are not in any server that accepts modmail through this bot.")?.await?;
+                    return Ok(());
+                },
+                [guild_id] => open_ticket(handler, guild_id, &message.author, message.channel_id).await?,
+                _ => {
+                    // we can't tell which server they mean, so ask them
+                    let buttons = guilds.iter().map(|guild_id| Component::Button(Button {
+                        custom_id: Some(format!("modmail_open:{}", guild_id)),
+                        disabled: false,
+                        emoji: None,
+                        label: Some(guild_name(handler, *guild_id)),
+                        style: ButtonStyle::Primary,
+                        url: None,
+                    })).collect::<Vec<_>>();
+                    // discord allows at most five buttons per row
+                    let rows = buttons.chunks(5).map(|chunk| Component::ActionRow(ActionRow {components: chunk.to_vec()})).collect::<Vec<_>>();
+                    handler.client.create_message(message.channel_id)
+                        .content("Which server's moderators would you like to contact?")?
+                        .components(&rows)?
+                        .await?;
+                    return Ok(());
+                },
+            }
+        },
+    };
+
+    let header = format!("**{}:** ", message.author.name);
+    relay(handler, &ticket, message, Id::new(ticket.thread_id), &header).await
+}
+
+async fn on_thread_message(handler: &BotHandler, message: &Message) -> anyhow::Result<()> {
+    let Some((user_id, ticket)) = ticket_for_thread(&handler.state, message.channel_id) else {
+        return Ok(());
+    };
+
+    // staff can talk amongst themselves in the thread by starting a message with //
+    if message.content.starts_with("//") {
+        append_transcript(&handler.state, ticket.thread_id, &format!("[internal] {}: {}", message.author.name, message.content));
+        return Ok(());
+    }
+
+    let header = format!("**{} (staff):** ", message.author.name);
+    relay(handler, &ticket, message, Id::new(ticket.dm_channel_id), &header).await.map_err(|e| {
+        anyhow!("could not DM user {}: {}", user_id, e)
+    })
+}
+
+/// Copy a message, attachments and all, to the other side of the ticket and write it to the
+/// transcript.
+async fn relay(handler: &BotHandler, ticket: &Ticket, message: &Message, destination: Id<ChannelMarker>, header: &str) -> anyhow::Result<()> {
+    let dir = transcript_dir(&handler.state, ticket.thread_id);
+    let mut attachments = Vec::new();
+    for (idx, attachment) in message.attachments.iter().enumerate() {
+        let path = dir.join(&attachment.filename);
+        let res = match download_file(&attachment.url, path.clone()).await {
+            Ok(()) => std::fs::read(&path).map_err(Into::into),
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(bytes) => attachments.push(Attachment::from_bytes(attachment.filename.clone(), bytes, idx as u64)),
+            Err(e) => tracing::error!("Error downloading modmail attachment \"{}\" on message {}: {}", attachment.filename, message.id, e),
+        }
+    }
+
+    let mut transcript_line = format!("{}: {}", message.author.name, message.content);
+    for attachment in message.attachments.iter() {
+        transcript_line.push_str(&format!(" [attachment: {}]", attachment.filename));
+    }
+    append_transcript(&handler.state, ticket.thread_id, &transcript_line);
+
+    let mut content = format!("{}{}", header, message.content);
+    if let Some((idx, _)) = content.char_indices().nth(MAX_MESSAGE_LENGTH) {
+        content.truncate(idx);
+    }
+    handler.client.create_message(destination).content(&content)?.attachments(&attachments)?.await?;
+    Ok(())
+}
+
+async fn open_ticket(handler: &BotHandler, guild_id: Id<GuildMarker>, user: &User, dm_channel_id: Id<ChannelMarker>) -> anyhow::Result<Ticket> {
+    let forum_id = get_config_channel(&handler.state, guild_id, "modmail_forum_id").ok_or(anyhow!("Modmail is not configured in guild {}", guild_id))?;
+
+    let intro = format!("Modmail ticket opened by {}.  Messages sent in this thread will be relayed to them, unless they start with //.", format_user(user));
+    let thread = handler.client.create_forum_thread(forum_id, &format!("{} ({})", user.name, user.id))
+        .message()
+        .content(&intro)?
+        .await?
+        .model()
+        .await?;
+
+    let ticket = Ticket {
+        guild_id: guild_id.get(),
+        thread_id: thread.channel.id.get(),
+        dm_channel_id: dm_channel_id.get(),
+        opened: SystemTime::now(),
+    };
+    TICKETS.update(&handler.state, |tickets| tickets.insert(user.id.get(), ticket.clone()));
+    let _ = std::fs::create_dir_all(transcript_dir(&handler.state, ticket.thread_id));
+
+    ModLogEntry::new(user, Some(thread.channel.id), ticket.opened, ModLogAction::ModmailOpened {
+        user_id: user.id.get(),
+        user_name: user.name.clone(),
+        user_discrim: user.discriminator,
+        thread_id: thread.channel.id.get(),
+    }).log(&handler.state);
+
+    if let Some(modlog_channel_id) = get_modlog_channel(&handler.state, guild_id) {
+        let builder = EmbedBuilder::new()
+            .title("Modmail ticket opened")
+            .field(EmbedField {name: "User".to_string(), value: format_user(user), inline: false})
+            .field(EmbedField {name: "Thread".to_string(), value: format!("<#{}>", thread.channel.id), inline: false});
+        let _pending = handler.state.metrics.modlog_post();
+        handler.client.create_message(modlog_channel_id).embeds(&[builder.build()])?.await?;
+    }
+
+    handler.client.create_message(dm_channel_id)
+        .content(&format!("You are now talking to the moderators of {}.  They will reply here.", guild_name(handler, guild_id)))?
+        .await?;
+
+    Ok(ticket)
+}
+
+/// Called when a user who is in several modmail-enabled servers picks which one to contact.
+pub(crate) async fn choose_guild(handler: Arc<BotHandler>, inter: Interaction, args: &str) -> anyhow::Result<()> {
+    let user = get_initiating_user(&inter)?;
+    let guild_id: Id<GuildMarker> = args.parse()?;
+    let dm_channel_id = inter.channel.as_ref().map(|channel| channel.id).ok_or(anyhow!("Modmail button pressed outside of a channel"))?;
+
+    if TICKETS.read(&handler.state, |tickets| tickets.contains_key(&user.id.get())) {
+        response!(handler, inter, "You already have an open ticket.  Just send your message here.");
+        return Ok(());
+    }
+
+    open_ticket(&handler, guild_id, user, dm_channel_id).await?;
+    response!(handler, inter, "Ticket opened.  Please send your message again so the moderators can see it.");
+    Ok(())
+}
+
+pub(crate) async fn modmail(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+
+    match ModmailCommand::from_interaction(data.into())? {
+        ModmailCommand::Forum(cmd) => {
+            let moderator_user = get_initiating_user(&inter)?;
+            if !is_server_admin(&handler, &inter) {
+                response!(ephemeral; handler, inter, "You need the Manage Server permission to change the bot's settings.");
+                return Ok(());
+            }
+            update_config_by(&handler, moderator_user, guild_id, |guild_config| guild_config["modmail_forum_id"] = toml_edit::value(cmd.channel.get() as i64)).await?;
+            response!(handler, inter, "Configuration successful.  Modmail tickets will now be opened in <#{}>.", cmd.channel);
+        },
+        ModmailCommand::Close(cmd) => {
+            let moderator_user = get_initiating_user(&inter)?;
+            if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "modmail")) {
+                response!(ephemeral; handler, inter, "You do not have permission to use that command.");
+                return Ok(());
+            }
+
+            let thread_id = inter.channel.as_ref().map(|channel| channel.id).ok_or(anyhow!("Command run outside of a channel"))?;
+            let Some((user_id, ticket)) = ticket_for_thread(&handler.state, thread_id) else {
+                response!(ephemeral; handler, inter, "This channel is not an open modmail ticket.");
+                return Ok(());
+            };
+            TICKETS.update(&handler.state, |tickets| tickets.remove(&user_id));
+
+            let reason = cmd.reason.unwrap_or_else(|| "No reason given.".to_owned());
+            append_transcript(&handler.state, ticket.thread_id, &format!("Ticket closed by {}: {}", moderator_user.name, reason));
+            let transcript = archive_transcript(&handler.state, user_id, &ticket);
+
+            let closing_message = format!("Your ticket with the moderators of {} has been closed.  {}", guild_name(&handler, guild_id), reason);
+            if let Err(e) = handler.client.create_message(Id::new(ticket.dm_channel_id)).content(&closing_message)?.await {
+                tracing::warn!("Couldn't tell user {} their ticket was closed: {}", user_id, e);
+            }
+
+            ModLogEntry::new(moderator_user, Some(thread_id), SystemTime::now(), ModLogAction::ModmailClosed {
+                user_id,
+                thread_id: ticket.thread_id,
+                reason: reason.clone(),
+                transcript: transcript.display().to_string(),
+            }).log(&handler.state);
+
+            if let Some(modlog_channel_id) = get_modlog_channel(&handler.state, guild_id) {
+                let builder = EmbedBuilder::new()
+                    .title("Modmail ticket closed")
+                    .description(reason)
+                    .field(EmbedField {name: "User".to_string(), value: format!("<@{}>", user_id), inline: false})
+                    .field(EmbedField {name: "Closed by".to_string(), value: format_user(moderator_user), inline: false})
+                    .field(EmbedField {name: "Thread".to_string(), value: format!("<#{}>", thread_id), inline: false});
+                let _pending = handler.state.metrics.modlog_post();
+                handler.client.create_message(modlog_channel_id).embeds(&[builder.build()])?.await?;
+            }
+
+            response!(handler, inter, "Ticket closed.");
+            handler.client.update_thread(thread_id).archived(true).locked(true).await?;
+        },
+    }
+    Ok(())
+}
+
+// kept out of the async functions above for the same reason as update_config()
+fn configured_modmail_guilds(state: &BotState) -> Vec<Id<GuildMarker>> {
+    let config = state.config.lock().unwrap();
+    config.iter()
+        .filter(|(_, guild_config)| guild_config.get("modmail_forum_id").is_some())
+        .filter_map(|(guild_id, _)| guild_id.parse().ok())
+        .collect()
+}
+
+fn ticket_for_thread(state: &BotState, thread_id: Id<ChannelMarker>) -> Option<(u64, Ticket)> {
+    TICKETS.read(state, |tickets| tickets.iter().find(|(_, ticket)| ticket.thread_id == thread_id.get()).map(|(user_id, ticket)| (*user_id, ticket.clone())))
+}
+
+fn transcript_dir(state: &BotState, thread_id: u64) -> PathBuf {
+    state.output_path.join("modmail").join(thread_id.to_string())
+}
+
+fn append_transcript(state: &BotState, thread_id: u64, line: &str) {
+    let dir = transcript_dir(state, thread_id);
+    let res = std::fs::create_dir_all(&dir)
+        .and_then(|()| std::fs::OpenOptions::new().create(true).append(true).open(dir.join("transcript.txt")))
+        .and_then(|mut file| writeln!(file, "[{}] {}", SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0), line));
+    if let Err(e) = res {
+        tracing::error!("Error writing to modmail transcript for thread {}: {}", thread_id, e);
+    }
+}
+
+/// Move a closed ticket's transcript directory somewhere that says whose it was and when it was
+/// closed, and return the new location.
+fn archive_transcript(state: &BotState, user_id: u64, ticket: &Ticket) -> PathBuf {
+    let closed = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let archived = state.output_path.join("modmail").join(format!("{}-{}-{}", ticket.guild_id, user_id, closed));
+    if let Err(e) = std::fs::rename(transcript_dir(state, ticket.thread_id), &archived) {
+        tracing::error!("Error archiving modmail transcript for thread {}: {}", ticket.thread_id, e);
+        return transcript_dir(state, ticket.thread_id);
+    }
+    archived
+}
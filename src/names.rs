@@ -0,0 +1,86 @@
+// helpers for comparing usernames and display names the way a human would see them, rather than
+// the way a computer does.
+
+/// Fold a name down to something we can compare: lowercase, with lookalike characters from other
+/// alphabets and leetspeak replaced by the plain ASCII letter they imitate, and everything that
+/// isn't a letter or digit thrown away.
+pub(crate) fn normalize(name: &str) -> String {
+    name.chars()
+        .flat_map(char::to_lowercase)
+        .map(unconfuse)
+        .filter(|c| c.is_ascii_alphanumeric())
+        .collect()
+}
+
+/// Map a character to the ASCII letter it's most likely standing in for.  This is nowhere near the
+/// full Unicode confusables table, just the ones people actually use.
+fn unconfuse(c: char) -> char {
+    match c {
+        // fullwidth forms
+        '\u{FF01}'..='\u{FF5E}' => char::from_u32(c as u32 - 0xFEE0).map(unconfuse).unwrap_or(c),
+        // cyrillic
+        'а' => 'a', 'в' => 'b', 'е' | 'ё' => 'e', 'к' => 'k', 'м' => 'm', 'н' => 'h', 'о' => 'o',
+        'р' => 'p', 'с' => 'c', 'т' => 't', 'у' => 'y', 'х' => 'x', 'і' | 'ї' => 'l', 'ј' => 'j',
+        'ѕ' => 's',
+        // greek
+        'α' => 'a', 'β' => 'b', 'ε' => 'e', 'η' => 'n', 'ι' => 'l', 'κ' => 'k', 'ν' => 'v',
+        'ο' => 'o', 'ρ' => 'p', 'τ' => 't', 'υ' => 'u', 'χ' => 'x',
+        // leetspeak, and letters that are hard to tell apart in Discord's font
+        '0' => 'o', '1' | '!' | '|' | 'i' => 'l', '3' => 'e', '4' | '@' => 'a', '5' | '$' => 's',
+        '7' => 't', '8' => 'b',
+        c => c,
+    }
+}
+
+/// Levenshtein distance between two strings, counted in chars.
+pub(crate) fn edit_distance(a: &str, b: &str) -> usize {
+    let b = b.chars().collect::<Vec<_>>();
+    let mut previous = (0..=b.len()).collect::<Vec<_>>();
+    let mut current = vec![0; b.len() + 1];
+    for (i, ca) in a.chars().enumerate() {
+        current[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + if ca == *cb {0} else {1};
+            current[j + 1] = substitution.min(previous[j + 1] + 1).min(current[j] + 1);
+        }
+        std::mem::swap(&mut previous, &mut current);
+    }
+    previous[b.len()]
+}
+
+/// Whether two names look alike once normalized: either identical, or within an edit distance
+/// that scales with how long they are.  Very short names match far too much to be worth comparing.
+pub(crate) fn looks_like(a: &str, b: &str) -> bool {
+    let (a, b) = (normalize(a), normalize(b));
+    if a.len() < 4 || b.len() < 4 {
+        return false;
+    }
+    a == b || edit_distance(&a, &b) <= a.len().max(b.len()) / 5
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_folds_lookalikes() {
+        assert_eq!(normalize("Ｓｔｒａｗｂｅｒｒｙ"), "strawberry");
+        assert_eq!(normalize("Ѕ7raw8erry"), "strawberry");
+        assert_eq!(normalize("mod_team 2"), "modteam2");
+    }
+
+    #[test]
+    fn edit_distance_counts_chars() {
+        assert_eq!(edit_distance("kitten", "sitting"), 3);
+        assert_eq!(edit_distance("", "abc"), 3);
+        assert_eq!(edit_distance("ааа", "aaa"), 3);
+    }
+
+    #[test]
+    fn looks_like_allows_small_differences() {
+        assert!(looks_like("Strawberry", "strawb3rrry"));
+        assert!(!looks_like("Strawberry", "Blueberry"));
+        // too short to compare at all
+        assert!(!looks_like("bob", "bob"));
+    }
+}
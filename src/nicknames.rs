@@ -0,0 +1,338 @@
+use std::sync::Arc;
+
+use twilight_interactions::command::CommandModel;
+use twilight_model::application::interaction::{application_command::CommandData, Interaction};
+use twilight_model::channel::message::embed::EmbedField;
+use twilight_model::gateway::payload::incoming::{MemberAdd, MemberUpdate};
+use twilight_model::id::Id;
+use twilight_model::id::marker::{GuildMarker, RoleMarker};
+use twilight_model::user::User;
+use twilight_util::builder::embed::EmbedBuilder;
+use twl_fw::response;
+
+use smb_log_format::ModLogAction;
+use crate::actions::{self, Action};
+use crate::business_logic::{bot_user, format_user, get_guild, get_initiating_user, is_server_admin, moderator_roles, moderator_users, post_to_modlog, update_config_by, Actor};
+use crate::commands::NicknamePolicyCommand;
+use crate::disk_log::ModLogEntryExt;
+use crate::history::{self, CaseKind};
+use crate::names;
+use crate::state::{BotHandler, BotState};
+
+/// What a member gets renamed to when there's nothing salvageable in any of their names.
+const FALLBACK_NICKNAME: &str = "Moderated nickname";
+
+/// A guild's display name rules.  Everything is off unless turned on with /nickname_policy.
+struct Policy {
+    dehoist: bool,
+    clean: bool,
+    impersonation: bool,
+    blocked_words: Vec<String>,
+}
+
+impl Policy {
+    fn is_empty(&self) -> bool {
+        !self.dehoist && !self.clean && !self.impersonation && self.blocked_words.is_empty()
+    }
+}
+
+// kept out of the async functions below for the same reason as update_config()
+fn policy(state: &BotState, guild_id: Id<GuildMarker>) -> Policy {
+    let config = state.config.lock().unwrap();
+    let guild_config = config.get(guild_id.get().to_string().as_str());
+    let flag = |key: &str| guild_config.and_then(|c| c.get(key)).and_then(|value| value.as_bool()).unwrap_or(false);
+    Policy {
+        dehoist: flag("nickname_dehoist"),
+        clean: flag("nickname_clean"),
+        impersonation: flag("nickname_impersonation"),
+        blocked_words: guild_config.and_then(|c| c.get("nickname_blocked_words"))
+            .and_then(|words| words.as_array())
+            .map(|words| words.iter().filter_map(|word| word.as_str()).map(str::to_owned).collect())
+            .unwrap_or_default(),
+    }
+}
+
+/// Zero width and blank characters people use to make names that are empty or look like someone
+/// else's.
+fn is_invisible(c: char) -> bool {
+    matches!(c,
+        '\u{00AD}' | '\u{034F}' | '\u{115F}' | '\u{1160}' | '\u{180E}' | '\u{200B}'..='\u{200F}' |
+        '\u{202A}'..='\u{202E}' | '\u{2060}'..='\u{2069}' | '\u{2800}' | '\u{3164}' | '\u{FEFF}' | '\u{FFA0}'
+    )
+}
+
+fn is_combining_mark(c: char) -> bool {
+    matches!(c, '\u{0300}'..='\u{036F}' | '\u{1AB0}'..='\u{1AFF}' | '\u{1DC0}'..='\u{1DFF}' | '\u{20D0}'..='\u{20FF}' | '\u{FE20}'..='\u{FE2F}')
+}
+
+/// Take out invisible characters, and zalgo: any stack of combining marks past the first, which
+/// leaves ordinary accented letters alone.
+fn clean(name: &str) -> String {
+    let mut cleaned = String::with_capacity(name.len());
+    let mut marks = 0;
+    for c in name.chars() {
+        if is_invisible(c) {
+            continue;
+        }
+        if is_combining_mark(c) {
+            marks += 1;
+            if marks > 1 {
+                continue;
+            }
+        } else {
+            marks = 0;
+        }
+        cleaned.push(c);
+    }
+    cleaned
+}
+
+/// Strip the punctuation people put at the start of their name to get sorted to the top of the
+/// member list.
+fn dehoist(name: &str) -> &str {
+    name.trim_start_matches(|c: char| !c.is_alphanumeric())
+}
+
+enum Verdict {
+    Fine,
+    /// The name breaks the rules, but can be fixed by cleaning it up.
+    Fixed(String, String),
+    /// The name breaks the rules and has to go entirely.
+    Unfixable(String),
+}
+
+fn judge(name: &str, policy: &Policy, staff_names: &[String]) -> Verdict {
+    let mut fixed = name.to_owned();
+    let mut why = Vec::new();
+    if policy.clean {
+        let cleaned = clean(&fixed);
+        if cleaned != fixed {
+            why.push("zalgo or invisible characters");
+            fixed = cleaned;
+        }
+    }
+    if policy.dehoist {
+        let dehoisted = dehoist(&fixed);
+        if dehoisted.len() != fixed.len() {
+            why.push("hoisting");
+            fixed = dehoisted.to_owned();
+        }
+    }
+    if fixed.trim().is_empty() {
+        return Verdict::Unfixable(format!("nothing left after removing {}", why.join(" and ")));
+    }
+
+    let normalized = names::normalize(&fixed);
+    if let Some(word) = policy.blocked_words.iter().find(|word| !names::normalize(word).is_empty() && normalized.contains(&names::normalize(word))) {
+        return Verdict::Unfixable(format!("contains blocked word \"{}\"", word));
+    }
+    if policy.impersonation {
+        if let Some(staff_name) = staff_names.iter().find(|staff_name| names::looks_like(&fixed, staff_name)) {
+            return Verdict::Unfixable(format!("impersonates staff member \"{}\"", staff_name));
+        }
+    }
+
+    if why.is_empty() {
+        Verdict::Fine
+    } else {
+        Verdict::Fixed(fixed, why.join(" and "))
+    }
+}
+
+/// Every name a moderator in the guild goes by, according to the cache.
+fn staff_names(handler: &BotHandler, guild_id: Id<GuildMarker>, mod_roles: &[u64], mod_users: &[u64]) -> Vec<String> {
+    let cache = handler.cache();
+    let Some(members) = cache.guild_members(guild_id) else {
+        return Vec::new();
+    };
+    let mut staff_names = Vec::new();
+    for user_id in members.iter() {
+        let Some(member) = cache.member(guild_id, *user_id) else { continue };
+        if !mod_users.contains(&user_id.get()) && !member.roles().iter().any(|role| mod_roles.contains(&role.get())) {
+            continue;
+        }
+        staff_names.extend(member.nick().map(str::to_owned));
+        if let Some(user) = cache.user(*user_id) {
+            staff_names.push(user.name.clone());
+            staff_names.extend(user.global_name.clone());
+        }
+    }
+    staff_names
+}
+
+/// Called for every GUILD_MEMBER_ADD.
+pub(crate) async fn on_member_add(handler: Arc<BotHandler>, add: MemberAdd) {
+    if let Err(e) = enforce(&handler, add.guild_id, &add.member.user, add.member.nick.as_deref(), &add.member.roles).await {
+        tracing::error!("Error enforcing nickname policy on {} in {}: {}", add.member.user.id, add.guild_id, e);
+    }
+}
+
+/// Called for every GUILD_MEMBER_UPDATE.  This includes the one we cause ourselves by renaming
+/// someone, but the new name passes the policy so it stops there.
+pub(crate) async fn on_member_update(handler: Arc<BotHandler>, update: MemberUpdate) {
+    if let Err(e) = enforce(&handler, update.guild_id, &update.user, update.nick.as_deref(), &update.roles).await {
+        tracing::error!("Error enforcing nickname policy on {} in {}: {}", update.user.id, update.guild_id, e);
+    }
+}
+
+async fn enforce(handler: &BotHandler, guild_id: Id<GuildMarker>, user: &User, nick: Option<&str>, roles: &[Id<RoleMarker>]) -> anyhow::Result<()> {
+    if user.bot {
+        return Ok(());
+    }
+    let policy = policy(&handler.state, guild_id);
+    if policy.is_empty() {
+        return Ok(());
+    }
+    // staff get to call themselves what they like
+    let mod_roles = moderator_roles(&handler.state, guild_id);
+    let mod_users = moderator_users(&handler.state, guild_id);
+    if mod_users.contains(&user.id.get()) || roles.iter().any(|role| mod_roles.contains(&role.get())) {
+        return Ok(());
+    }
+    let staff_names = if policy.impersonation {staff_names(handler, guild_id, &mod_roles, &mod_users)} else {Vec::new()};
+
+    let account_name = user.global_name.as_deref().unwrap_or(&user.name);
+    let (new_nick, why) = match judge(nick.unwrap_or(account_name), &policy, &staff_names) {
+        Verdict::Fine => return Ok(()),
+        Verdict::Fixed(fixed, why) => (Some(fixed), why),
+        // if it was the nickname that was the problem, fall back to their account name, as long as
+        // that's any better
+        Verdict::Unfixable(why) => match nick.map(|_| judge(account_name, &policy, &staff_names)) {
+            Some(Verdict::Fine) => (None, why),
+            Some(Verdict::Fixed(fixed, _)) => (Some(fixed), why),
+            _ => (Some(FALLBACK_NICKNAME.to_owned()), why),
+        },
+    };
+    if new_nick.as_deref() == nick {
+        return Ok(());
+    }
+
+    let reason = format!("Nickname policy: {}", why);
+    let actor = Actor::Bot(bot_user(handler)?);
+    let action = Action::SetNickname {user_id: user.id, nick: new_nick.as_deref(), reason: &reason};
+    if !actions::execute(handler, guild_id, Some("nickname_policy"), &actor, action).await? {
+        return Ok(());
+    }
+
+    let describe = |name: Option<&str>| name.map_or_else(|| "(none)".to_owned(), |name| format!("`{}`", name));
+    actor.log_entry(None, ModLogAction::NicknameChange {
+        user_id: user.id.get(),
+        before: nick.map(str::to_owned),
+        after: new_nick.clone(),
+        reason: why.clone(),
+    }).log(&handler.state);
+    history::record(&handler.state, guild_id, user.id, None, CaseKind::Nickname, format!("{} -> {}: {}", describe(nick), describe(new_nick.as_deref()), why));
+
+    let builder = EmbedBuilder::new()
+        .title("Nickname changed")
+        .description(why)
+        .field(EmbedField {name: "Member".to_string(), value: format_user(user), inline: false})
+        .field(EmbedField {name: "Before".to_string(), value: describe(nick), inline: true})
+        .field(EmbedField {name: "After".to_string(), value: describe(new_nick.as_deref()), inline: true});
+    post_to_modlog(handler, guild_id, builder.build()).await?;
+    Ok(())
+}
+
+pub(crate) async fn nickname_policy(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+
+    if !is_server_admin(&handler, &inter) {
+        response!(ephemeral; handler, inter, "You need the Manage Server permission to change the bot's settings.");
+        return Ok(());
+    }
+
+    let message = match NicknamePolicyCommand::from_interaction(data.into())? {
+        NicknamePolicyCommand::Rules(cmd) => {
+            update_config_by(&handler, moderator_user, guild_id, |guild_config| {
+                for (key, value) in [("nickname_dehoist", cmd.dehoist), ("nickname_clean", cmd.clean), ("nickname_impersonation", cmd.impersonation)] {
+                    if let Some(value) = value {
+                        guild_config[key] = toml_edit::value(value);
+                    }
+                }
+            }).await?;
+            let policy = policy(&handler.state, guild_id);
+            let on_off = |flag: bool| if flag {"on"} else {"off"};
+            format!("Dehoisting is {}, removing zalgo and invisible characters is {}, and staff impersonation checks are {}.  Blocked words: {}",
+                on_off(policy.dehoist), on_off(policy.clean), on_off(policy.impersonation),
+                if policy.blocked_words.is_empty() {"none".to_owned()} else {policy.blocked_words.iter().map(|word| format!("`{}`", word)).collect::<Vec<_>>().join(", ")})
+        },
+        NicknamePolicyCommand::Block(cmd) => {
+            let word = cmd.word.trim().to_lowercase();
+            update_config_by(&handler, moderator_user, guild_id, |guild_config| {
+                let Some(words) = guild_config.as_table_mut().and_then(|table| table.entry("nickname_blocked_words").or_insert(toml_edit::value(toml_edit::Array::new())).as_array_mut()) else {
+                    return "Config file is not in a valid format.  No changes were made.".to_owned();
+                };
+                if words.iter().any(|w| w.as_str() == Some(word.as_str())) {
+                    "That word is already blocked.".to_owned()
+                } else {
+                    words.push(word.as_str());
+                    format!("Names containing `{}` will now be reset.", word)
+                }
+            }).await?
+        },
+        NicknamePolicyCommand::Unblock(cmd) => {
+            let word = cmd.word.trim().to_lowercase();
+            update_config_by(&handler, moderator_user, guild_id, |guild_config| {
+                let Some(words) = guild_config.as_table_mut().and_then(|table| table.entry("nickname_blocked_words").or_insert(toml_edit::value(toml_edit::Array::new())).as_array_mut()) else {
+                    return "Config file is not in a valid format.  No changes were made.".to_owned();
+                };
+                if let Some(idx) = words.iter().position(|w| w.as_str() == Some(word.as_str())) {
+                    words.remove(idx);
+                    format!("`{}` is no longer blocked.", word)
+                } else {
+                    "That word is already not blocked.".to_owned()
+                }
+            }).await?
+        },
+    };
+
+    response!(ephemeral; handler, inter, "{}", message);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn no_rules() -> Policy {
+        Policy {
+            dehoist: false,
+            clean: false,
+            impersonation: false,
+            blocked_words: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn clean_keeps_single_accents() {
+        assert_eq!(clean("he\u{200B}llo"), "hello");
+        assert_eq!(clean("e\u{0301}"), "e\u{0301}");
+        assert_eq!(clean("e\u{0301}\u{0302}\u{0303}x"), "e\u{0301}x");
+    }
+
+    #[test]
+    fn dehoist_strips_leading_punctuation() {
+        assert_eq!(dehoist("!!! zed"), "zed");
+        assert_eq!(dehoist("zed!"), "zed!");
+    }
+
+    #[test]
+    fn judge_fixes_what_it_can() {
+        let policy = Policy {dehoist: true, clean: true, ..no_rules()};
+        assert!(matches!(judge("Fine name", &policy, &[]), Verdict::Fine));
+        assert!(matches!(judge("!!Bob", &policy, &[]), Verdict::Fixed(name, why) if name == "Bob" && why == "hoisting"));
+        assert!(matches!(judge("!!\u{200B}", &policy, &[]), Verdict::Unfixable(_)));
+    }
+
+    #[test]
+    fn judge_catches_blocked_words_and_impersonation() {
+        let policy = Policy {blocked_words: vec!["badword".to_owned()], ..no_rules()};
+        assert!(matches!(judge("xB4dW0rdx", &policy, &[]), Verdict::Unfixable(_)));
+
+        let staff = ["Strawberry".to_owned()];
+        assert!(matches!(judge("Strawb3rry", &no_rules(), &staff), Verdict::Fine));
+        let policy = Policy {impersonation: true, ..no_rules()};
+        assert!(matches!(judge("Strawb3rry", &policy, &staff), Verdict::Unfixable(_)));
+    }
+}
@@ -0,0 +1,104 @@
+use std::marker::PhantomData;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::io::Write;
+
+use serde::{Serialize, de::DeserializeOwned};
+
+use crate::state::BotState;
+
+/// A piece of bot state that lives in a MessagePack file under `OUTPUT_DIR` and is written back to
+/// disk every time it is modified.  The static is only a name for it: the value itself belongs to
+/// a bot, in its BotState, which is why every access is handed one.
+pub(crate) struct Persisted<T> {
+    filename: &'static str,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T: Serialize + DeserializeOwned + Default + Send + 'static> Persisted<T> {
+    pub(crate) const fn new(filename: &'static str) -> Self {
+        Self {
+            filename,
+            _value: PhantomData,
+        }
+    }
+
+    fn get(&self, state: &BotState) -> Arc<Mutex<T>> {
+        let mut stores = state.stores.lock().unwrap();
+        let value = stores.entry(self.filename).or_insert_with(|| {
+            let path = state.output_path.join(self.filename);
+            let value = match std::fs::read(&path) {
+                Ok(bytes) => rmp_serde::from_slice(&bytes).unwrap_or_else(|e| {
+                    tracing::error!("{} is corrupt ({}).  Starting over with an empty one.", path.display(), e);
+                    T::default()
+                }),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => T::default(),
+                Err(e) => {
+                    tracing::error!("Error reading {}: {}.  Starting over with an empty one.", path.display(), e);
+                    T::default()
+                }
+            };
+            Arc::new(Mutex::new(value))
+        });
+        // two statics with the same file name would end up here
+        value.clone().downcast().unwrap_or_else(|_| panic!("{} is used as two different types", self.filename))
+    }
+
+    /// Look at the stored value without writing it back out.
+    pub(crate) fn read<R>(&self, state: &BotState, action: impl FnOnce(&T) -> R) -> R {
+        action(&self.get(state).lock().unwrap())
+    }
+
+    /// Modify the stored value and flush it to disk.
+    pub(crate) fn update<R>(&self, state: &BotState, action: impl FnOnce(&mut T) -> R) -> R {
+        let store = self.get(state);
+        let mut value = store.lock().unwrap();
+        let res = action(&mut value);
+        if let Err(e) = self.save(state, &value) {
+            tracing::error!("Failed to flush {} to disk!  Error message was: {}.  Continuing anyway.", self.filename, e);
+        }
+        res
+    }
+
+    fn save(&self, state: &BotState, value: &T) -> anyhow::Result<()> {
+        let bytes = rmp_serde::to_vec_named(value)?;
+        write_atomically(&state.output_path.join(self.filename), &bytes)?;
+        Ok(())
+    }
+}
+
+/// Per-bot state that only lives in memory, for things that don't matter once the bot restarts.
+/// Like a Persisted, the static is only a name and the value lives in the bot's BotState.
+pub(crate) struct Scratch<T> {
+    name: &'static str,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T: Default + Send + 'static> Scratch<T> {
+    pub(crate) const fn new(name: &'static str) -> Self {
+        Self {
+            name,
+            _value: PhantomData,
+        }
+    }
+
+    pub(crate) fn update<R>(&self, state: &BotState, action: impl FnOnce(&mut T) -> R) -> R {
+        let store = state.stores.lock().unwrap()
+            .entry(self.name)
+            .or_insert_with(|| Arc::new(Mutex::new(T::default())))
+            .clone();
+        let store = store.downcast::<Mutex<T>>().unwrap_or_else(|_| panic!("{} is used as two different types", self.name));
+        let mut value = store.lock().unwrap();
+        action(&mut value)
+    }
+}
+
+/// Write a file by writing to a temporary file and renaming it over the real one, so a crash
+/// halfway through can't leave us with a truncated file.
+pub(crate) fn write_atomically(path: &Path, bytes: &[u8]) -> std::io::Result<()> {
+    let tmp_path = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&tmp_path)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    std::fs::rename(&tmp_path, path)
+}
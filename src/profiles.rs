@@ -0,0 +1,64 @@
+use std::path::{Path, PathBuf};
+
+// one process can run several bots, each with its own token, config file and output directory.
+// they're listed in a profiles file like this one:
+//
+//     [[bot]]
+//     name = "partner-a"
+//     token_env = "PARTNER_A_TOKEN"
+//     config = "partner-a/config.toml"
+//     output_dir = "partner-a"
+//
+// the token can be given directly with `token = "..."` instead, but then it's sitting in a file, so
+// token_env is better.  relative paths are relative to the profiles file.
+
+pub(crate) struct Profile {
+    /// Only used to tell the bots apart in the logs.
+    pub(crate) name: String,
+    pub(crate) token: String,
+    pub(crate) config: PathBuf,
+    pub(crate) output_dir: PathBuf,
+}
+
+/// Read every profile in a profiles file.  Errors are messages for whoever's starting the bot.
+pub(crate) fn read_profiles(path: &Path) -> Result<Vec<Profile>, String> {
+    let contents = std::fs::read_to_string(path).map_err(|e| format!("Error reading {}: {}", path.display(), e))?;
+    let document: toml_edit::Document = contents.parse().map_err(|e| format!("{} did not contain valid TOML.  Specific error was: {}", path.display(), e))?;
+    let base = path.parent().unwrap_or(Path::new(""));
+
+    let bots = document.get("bot")
+        .and_then(|bots| bots.as_array_of_tables())
+        .ok_or(format!("{} has no [[bot]] entries", path.display()))?;
+
+    let mut profiles = Vec::new();
+    for (idx, bot) in bots.iter().enumerate() {
+        let name = bot.get("name").and_then(|name| name.as_str()).map_or_else(|| format!("bot {}", idx + 1), str::to_owned);
+        let get_str = |key: &str| bot.get(key).and_then(|value| value.as_str());
+
+        let token = match (get_str("token"), get_str("token_env")) {
+            (Some(token), None) => token.to_owned(),
+            (None, Some(var)) => std::env::var(var).map_err(|e| format!("Profile {}: can't read its token from environment variable {}: {}", name, var, e))?,
+            _ => return Err(format!("Profile {} needs exactly one of token and token_env", name)),
+        };
+        let config = get_str("config").ok_or(format!("Profile {} has no config file", name))?;
+        let output_dir = get_str("output_dir").ok_or(format!("Profile {} has no output_dir", name))?;
+
+        if profiles.iter().any(|other: &Profile| other.name == name) {
+            return Err(format!("There are two profiles called {}", name));
+        }
+        profiles.push(Profile {
+            name,
+            token,
+            config: base.join(config),
+            output_dir: base.join(output_dir),
+        });
+    }
+
+    // two bots writing the same files would trample each other
+    for (idx, profile) in profiles.iter().enumerate() {
+        if let Some(other) = profiles[..idx].iter().find(|other| other.config == profile.config || other.output_dir == profile.output_dir) {
+            return Err(format!("Profiles {} and {} share a config file or output directory", other.name, profile.name));
+        }
+    }
+    Ok(profiles)
+}
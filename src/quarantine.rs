@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::SystemTime;
+
+use serde::{Serialize, Deserialize};
+use twilight_interactions::command::CommandModel;
+use twilight_model::application::interaction::{application_command::CommandData, Interaction};
+use twilight_model::channel::message::embed::EmbedField;
+use twilight_model::id::Id;
+use twilight_model::id::marker::{GuildMarker, RoleMarker, UserMarker};
+use twilight_util::builder::embed::EmbedBuilder;
+use twl_fw::response;
+
+use smb_log_format::ModLogAction;
+use crate::actions::{self, Action};
+use crate::business_logic::{get_config_role, get_guild, get_initiating_user, is_allowed_to_use, is_server_admin, post_to_modlog, update_config_by, Actor};
+use crate::commands::{QuarantineCommand, QuarantineRoleCommand, ReleaseCommand};
+use crate::disk_log::ModLogEntryExt;
+use crate::history::{self, CaseKind};
+use crate::persist::Persisted;
+use crate::state::BotHandler;
+
+/// Quarantined members, keyed by (guild ID, user ID).
+static QUARANTINED: Persisted<HashMap<(u64, u64), Quarantine>> = Persisted::new("quarantined.msgpack");
+
+#[derive(Serialize, Deserialize, Clone)]
+struct Quarantine {
+    /// The member's roles from before they were quarantined, minus any managed ones we left alone.
+    roles: Vec<u64>,
+    quarantine_role: u64,
+    reason: String,
+    since: SystemTime,
+}
+
+fn role_mentions(roles: &[u64]) -> String {
+    if roles.is_empty() {
+        "None".to_owned()
+    } else {
+        roles.iter().map(|id| format!("<@&{}>", id)).collect::<Vec<_>>().join(" ")
+    }
+}
+
+pub(crate) async fn quarantine(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "quarantine")) {
+        response!(ephemeral; handler, inter, "You do not have permission to use that command.");
+        return Ok(());
+    }
+
+    let cmd = QuarantineCommand::from_interaction(data.into())?;
+    let user_id = cmd.user;
+    let reason = cmd.reason.unwrap_or_else(|| "No reason given.".to_owned());
+
+    let Some(quarantine_role) = get_config_role(&handler.state, guild_id, "quarantine_role_id") else {
+        response!(ephemeral; handler, inter, "No quarantine role has been set up in this server.  Set one with /quarantine_role.");
+        return Ok(());
+    };
+    if QUARANTINED.read(&handler.state, |quarantined| quarantined.contains_key(&(guild_id.get(), user_id.get()))) {
+        response!(ephemeral; handler, inter, "<@{}> is already quarantined.", user_id);
+        return Ok(());
+    }
+
+    let member = handler.client.guild_member(guild_id, user_id).await?.model().await?;
+    // managed roles (bot roles, server booster, etc) can't be taken away, so leave them be
+    let managed = handler.client.roles(guild_id).await?.models().await?.into_iter()
+        .filter(|role| role.managed)
+        .map(|role| role.id)
+        .collect::<Vec<_>>();
+    let (kept, removed): (Vec<Id<RoleMarker>>, Vec<Id<RoleMarker>>) = member.roles.iter().copied().partition(|role| managed.contains(role));
+
+    // write the snapshot down before changing anything so a crash can't lose it
+    let record = Quarantine {
+        roles: removed.iter().map(|id| id.get()).collect(),
+        quarantine_role: quarantine_role.get(),
+        reason: reason.clone(),
+        since: SystemTime::now(),
+    };
+    QUARANTINED.update(&handler.state, |quarantined| quarantined.insert((guild_id.get(), user_id.get()), record.clone()));
+
+    let mut new_roles = kept;
+    new_roles.push(quarantine_role);
+    let done = actions::execute(&handler, guild_id, None, &Actor::User(moderator_user), Action::SetRoles {user_id, roles: &new_roles}).await;
+    if !matches!(done, Ok(true)) {
+        // nothing changed, so they aren't quarantined after all
+        QUARANTINED.update(&handler.state, |quarantined| quarantined.remove(&(guild_id.get(), user_id.get())));
+    }
+    if !done? {
+        response!(ephemeral; handler, inter, "Shadow mode is on, so <@{}> was not quarantined.  What would have happened has been posted to the modlog channel.", user_id);
+        return Ok(());
+    }
+
+    Actor::User(moderator_user).log_entry(None, ModLogAction::Quarantine {
+        user_id: user_id.get(),
+        roles: record.roles.clone(),
+        reason: reason.clone(),
+    }).log(&handler.state);
+    history::record(&handler.state, guild_id, user_id, Some(moderator_user.id), CaseKind::Quarantine, reason.clone());
+
+    let builder = EmbedBuilder::new()
+        .title("Member quarantined")
+        .description(reason)
+        .field(EmbedField {name: "Member".to_string(), value: format!("<@{}>", user_id), inline: false})
+        .field(EmbedField {name: "Quarantined by".to_string(), value: Actor::User(moderator_user).describe(), inline: false})
+        .field(EmbedField {name: "Roles removed".to_string(), value: role_mentions(&record.roles), inline: false});
+    post_to_modlog(&handler, guild_id, builder.build()).await?;
+
+    response!(ephemeral; handler, inter, "<@{}> has been quarantined.", user_id);
+    Ok(())
+}
+
+pub(crate) async fn release(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "release")) {
+        response!(ephemeral; handler, inter, "You do not have permission to use that command.");
+        return Ok(());
+    }
+
+    let cmd = ReleaseCommand::from_interaction(data.into())?;
+    let user_id = cmd.user;
+    let reason = cmd.reason.unwrap_or_else(|| "No reason given.".to_owned());
+
+    if release_member(&handler, guild_id, user_id, Actor::User(moderator_user), reason).await? {
+        response!(ephemeral; handler, inter, "<@{}> has been released from quarantine.", user_id);
+    } else {
+        response!(ephemeral; handler, inter, "<@{}> is not quarantined.", user_id);
+    }
+    Ok(())
+}
+
+/// Give a quarantined member their old roles back.  Returns false if they weren't quarantined.
+async fn release_member(handler: &BotHandler, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>, actor: Actor<'_>, reason: String) -> anyhow::Result<bool> {
+    let Some(record) = QUARANTINED.read(&handler.state, |quarantined| quarantined.get(&(guild_id.get(), user_id.get())).cloned()) else {
+        return Ok(false);
+    };
+
+    let member = handler.client.guild_member(guild_id, user_id).await?.model().await?;
+    // keep anything they picked up while quarantined (managed roles, mostly), minus the quarantine
+    // role itself, and add back everything we took away
+    let mut roles = member.roles.into_iter().filter(|role| role.get() != record.quarantine_role).collect::<Vec<_>>();
+    for role in record.roles.iter() {
+        if !roles.iter().any(|existing| existing.get() == *role) {
+            roles.push(Id::new(*role));
+        }
+    }
+    handler.client.update_guild_member(guild_id, user_id).roles(&roles).await?;
+    QUARANTINED.update(&handler.state, |quarantined| quarantined.remove(&(guild_id.get(), user_id.get())));
+
+    actor.log_entry(None, ModLogAction::Release {
+        user_id: user_id.get(),
+        roles: record.roles.clone(),
+        reason: reason.clone(),
+    }).log(&handler.state);
+    history::record(&handler.state, guild_id, user_id, actor.user_id(), CaseKind::Release, reason.clone());
+
+    let builder = EmbedBuilder::new()
+        .title("Member released from quarantine")
+        .description(reason)
+        .field(EmbedField {name: "Member".to_string(), value: format!("<@{}>", user_id), inline: false})
+        .field(EmbedField {name: "Released by".to_string(), value: actor.describe(), inline: false})
+        .field(EmbedField {name: "Roles restored".to_string(), value: role_mentions(&record.roles), inline: false});
+    post_to_modlog(handler, guild_id, builder.build()).await?;
+    Ok(true)
+}
+
+pub(crate) async fn quarantine_role(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+    if !is_server_admin(&handler, &inter) {
+        response!(ephemeral; handler, inter, "You need the Manage Server permission to change the bot's settings.");
+        return Ok(());
+    }
+
+    let cmd = QuarantineRoleCommand::from_interaction(data.into())?;
+    let role_id = cmd.role;
+
+    update_config_by(&handler, moderator_user, guild_id, |guild_config| guild_config["quarantine_role_id"] = toml_edit::value(role_id.get() as i64)).await?;
+
+    response!(ephemeral; handler, inter, "Configuration successful.  Quarantined members will now be given <@&{}>.", role_id);
+    Ok(())
+}
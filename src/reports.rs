@@ -0,0 +1,307 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Serialize, Deserialize};
+use twilight_model::application::interaction::{application_command::CommandData, modal::ModalInteractionData, Interaction};
+use twilight_model::channel::Message;
+use twilight_model::channel::message::component::{ActionRow, Button, ButtonStyle, Component, TextInput, TextInputStyle};
+use twilight_model::channel::message::embed::EmbedField;
+use twilight_model::id::Id;
+use twilight_model::id::marker::{ChannelMarker, GuildMarker, MessageMarker, UserMarker};
+use twilight_model::user::User;
+use twilight_util::builder::embed::EmbedBuilder;
+use twl_fw::response;
+
+use anyhow::anyhow;
+
+use smb_log_format::{ModLogEntry, ModLogAction, ModLogMessage, ReportResolution};
+use crate::actions::{self, Action};
+use crate::business_logic::{format_user, guild_name, get_config_channel, get_config_integer, get_guild, get_initiating_user, get_modlog_channel, is_allowed_to_use, modal_value, resolve_buttons, respond_with_modal, Actor};
+use crate::history::{self, CaseKind};
+use crate::disk_log::{ModLogEntryExt, ModLogMessageExt};
+use crate::persist::Persisted;
+use crate::state::BotHandler;
+
+/// How long the Timeout button mutes someone for if the guild hasn't set `report_timeout_minutes`.
+const DEFAULT_TIMEOUT_MINUTES: i64 = 60;
+
+/// Reports that have been posted to a reports channel but not acted on yet, keyed by the ID of the
+/// message in the reports channel.
+static PENDING_REPORTS: Persisted<HashMap<u64, PendingReport>> = Persisted::new("pending_reports.msgpack");
+
+#[derive(Serialize, Deserialize)]
+struct PendingReport {
+    guild_id: u64,
+    reporter_id: u64,
+    reporter_name: String,
+    reporter_discrim: u16,
+    reason: String,
+    // everything below here is a copy of the ModLogMessage we captured when the report was filed.
+    // we keep our own copy since the original message may well be gone by the time a moderator
+    // gets around to it.
+    channel_id: u64,
+    message_id: u64,
+    content: String,
+    author_id: u64,
+    author_name: String,
+    author_discrim: u16,
+    attachments: Vec<String>,
+}
+
+impl PendingReport {
+    fn to_modlog_message(&self) -> ModLogMessage {
+        ModLogMessage {
+            id: self.message_id,
+            content: self.content.clone(),
+            author_id: self.author_id,
+            author_name: self.author_name.clone(),
+            author_discrim: self.author_discrim,
+            attachments: self.attachments.clone(),
+        }
+    }
+}
+
+pub(crate) async fn report_message(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+
+    if get_config_channel(&handler.state, guild_id, "reports_channel_id").is_none() {
+        response!(ephemeral; handler, inter, "Reporting messages has not been set up in this server yet.  Please contact a moderator directly.");
+        return Ok(());
+    }
+
+    // incantation to get the message object we were invoked with
+    let message = data.resolved.unwrap().messages.remove(&Id::new(data.target_id.unwrap().get())).unwrap();
+
+    let reason_input = TextInput {
+        custom_id: "reason".into(),
+        label: "What is wrong with this message?".into(),
+        max_length: Some(1000),
+        min_length: Some(1),
+        placeholder: None,
+        required: Some(true),
+        style: TextInputStyle::Paragraph,
+        value: None,
+    };
+
+    respond_with_modal(&handler, &inter, format!("report:{}:{}", message.channel_id, message.id), "Report message".into(), vec![reason_input]).await
+}
+
+/// Called when a member submits the modal opened by [report_message].
+pub(crate) async fn submit_report(handler: Arc<BotHandler>, inter: Interaction, data: ModalInteractionData, args: &str) -> anyhow::Result<()> {
+    let guild_id = inter.guild_id.ok_or(anyhow!("Report submitted outside of a guild"))?;
+    let reporter = get_initiating_user(&inter)?;
+
+    let (channel_id, message_id) = args.split_once(':').ok_or(anyhow!("Malformed report custom ID: {}", args))?;
+    let channel_id: Id<ChannelMarker> = channel_id.parse()?;
+    let message_id: Id<MessageMarker> = message_id.parse()?;
+    let reason = modal_value(&data, "reason").unwrap_or_default().to_owned();
+
+    let Some(reports_channel_id) = get_config_channel(&handler.state, guild_id, "reports_channel_id") else {
+        response!(ephemeral; handler, inter, "Reporting messages has not been set up in this server yet.  Please contact a moderator directly.");
+        return Ok(());
+    };
+
+    let message = match handler.client.message(channel_id, message_id).await {
+        Ok(resp) => resp.model().await?,
+        Err(e) => {
+            tracing::warn!("Couldn't fetch reported message {}: {}", message_id, e);
+            response!(ephemeral; handler, inter, "That message could not be found.  It may already have been deleted.");
+            return Ok(());
+        }
+    };
+
+    // capture the message (and download its attachments) now, while it still exists
+    let captured = ModLogMessage::from_message(&handler.state, &message).await;
+
+    let report_message = handler.client.create_message(reports_channel_id)
+        .embeds(&[report_embed(&message, reporter, &reason)])?
+        .components(&[report_buttons()])?
+        .await?
+        .model()
+        .await?;
+
+    PENDING_REPORTS.update(&handler.state, |reports| reports.insert(report_message.id.get(), PendingReport {
+        guild_id: guild_id.get(),
+        reporter_id: reporter.id.get(),
+        reporter_name: reporter.name.clone(),
+        reporter_discrim: reporter.discriminator,
+        reason,
+        channel_id: channel_id.get(),
+        message_id: captured.id,
+        content: captured.content,
+        author_id: captured.author_id,
+        author_name: captured.author_name,
+        author_discrim: captured.author_discrim,
+        attachments: captured.attachments,
+    }));
+
+    response!(ephemeral; handler, inter, "Thank you.  Your report has been sent to the moderators.");
+    Ok(())
+}
+
+fn report_embed(message: &Message, reporter: &twilight_model::user::User, reason: &str) -> twilight_model::channel::message::Embed {
+    let mut builder = EmbedBuilder::new()
+        .title("Message reported by member")
+        .description(message.content.clone())
+        .field(EmbedField {name: "Sent by".to_string(), value: format_user(&message.author), inline: false})
+        .field(EmbedField {name: "Reported by".to_string(), value: format_user(reporter), inline: false})
+        .field(EmbedField {name: "Reason".to_string(), value: reason.to_owned(), inline: false})
+        .field(EmbedField {name: "Channel".to_string(), value: format!("<#{}>", message.channel_id), inline: false});
+    if let Some(guild_id) = message.guild_id {
+        builder = builder.field(EmbedField {name: "Link".to_string(), value: format!("https://discord.com/channels/{}/{}/{}", guild_id, message.channel_id, message.id), inline: false});
+    }
+    for attachment in message.attachments.iter() {
+        builder = builder.field(EmbedField {name: format!("Attachment: {}", attachment.filename), value: attachment.proxy_url.clone(), inline: false});
+    }
+    builder.build()
+}
+
+fn report_buttons() -> Component {
+    let button = |action: &str, label: &str, style| Component::Button(Button {
+        custom_id: Some(format!("report_action:{}", action)),
+        disabled: false,
+        emoji: None,
+        label: Some(label.to_owned()),
+        style,
+        url: None,
+    });
+    Component::ActionRow(ActionRow {
+        components: vec![
+            button("delete", "Delete", ButtonStyle::Danger),
+            button("warn", "Warn", ButtonStyle::Primary),
+            button("timeout", "Timeout", ButtonStyle::Primary),
+            button("dismiss", "Dismiss", ButtonStyle::Secondary),
+        ],
+    })
+}
+
+/// Put a report back in the pending list after shadow mode stopped a moderator's action on it, so
+/// it can still be acted on for real.
+async fn leave_open(handler: &BotHandler, inter: &Interaction, report_message_id: Id<MessageMarker>, report: PendingReport) -> anyhow::Result<()> {
+    PENDING_REPORTS.update(&handler.state, |reports| reports.insert(report_message_id.get(), report));
+    response!(ephemeral; handler, inter, "Shadow mode is on, so nothing was done.  What would have happened has been posted to the modlog channel, and the report is still open.");
+    Ok(())
+}
+
+/// Carry out what a report button asks for.  Returns None if shadow mode stopped it.
+async fn resolve(handler: &BotHandler, guild_id: Id<GuildMarker>, moderator_user: &User, action: &str, report: &PendingReport) -> anyhow::Result<Option<ReportResolution>> {
+    let author_id: Id<UserMarker> = Id::new(report.author_id);
+    let channel_id: Id<ChannelMarker> = Id::new(report.channel_id);
+
+    Ok(Some(match action {
+        "delete" => {
+            let action = Action::DeleteMessage {channel_id, message_id: Id::new(report.message_id)};
+            if !actions::execute(handler, guild_id, None, &Actor::User(moderator_user), action).await? {
+                return Ok(None);
+            }
+            ReportResolution::Deleted
+        },
+        "warn" => {
+            let guild_name = guild_name(handler, guild_id);
+            let text = format!("You have been warned by the moderators of {} for the following message:\n> {}\nReason: {}", guild_name, report.content, report.reason);
+            match actions::execute(handler, guild_id, None, &Actor::User(moderator_user), Action::DirectMessage {user_id: author_id, content: &text}).await {
+                Ok(true) => (),
+                Ok(false) => return Ok(None),
+                // the warning still stands on the record even if they don't take DMs
+                Err(e) => tracing::warn!("Couldn't DM warning to user {}: {}", author_id, e),
+            }
+            ReportResolution::Warned
+        },
+        "timeout" => {
+            let minutes = get_config_integer(&handler.state, guild_id, "report_timeout_minutes").unwrap_or(DEFAULT_TIMEOUT_MINUTES);
+            let until = SystemTime::now() + Duration::from_secs(minutes as u64 * 60);
+            if !actions::execute(handler, guild_id, None, &Actor::User(moderator_user), Action::Timeout {user_id: author_id, until}).await? {
+                return Ok(None);
+            }
+            ReportResolution::TimedOut {until}
+        },
+        "dismiss" => ReportResolution::Dismissed,
+        other => return Err(anyhow!("Unknown report action {}", other)),
+    }))
+}
+
+/// Called when a moderator presses one of the buttons under a report.
+pub(crate) async fn report_action(handler: Arc<BotHandler>, inter: Interaction, action: &str) -> anyhow::Result<()> {
+    let guild_id = inter.guild_id.ok_or(anyhow!("Report button pressed outside of a guild"))?;
+    let moderator_user = get_initiating_user(&inter)?;
+
+    // dismissing a report is about as serious as a warning, so it goes by the same permission
+    let permission = match action {
+        "delete" => "Delete message",
+        "timeout" => "timeout",
+        _ => "warn",
+    };
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, permission)) {
+        response!(ephemeral; handler, inter, "You do not have permission to use that command.");
+        return Ok(());
+    }
+
+    let report_message = inter.message.as_ref().ok_or(anyhow!("Button interaction without a message"))?;
+    // take the report out of the pending list right away so two moderators can't both act on it
+    let Some(report) = PENDING_REPORTS.update(&handler.state, |reports| reports.remove(&report_message.id.get())) else {
+        response!(ephemeral; handler, inter, "This report has already been handled.");
+        return Ok(());
+    };
+
+    let author_id: Id<UserMarker> = Id::new(report.author_id);
+    let channel_id: Id<ChannelMarker> = Id::new(report.channel_id);
+
+    let resolution = match resolve(&handler, guild_id, moderator_user, action, &report).await {
+        Ok(Some(resolution)) => resolution,
+        Ok(None) => return leave_open(&handler, &inter, report_message.id, report).await,
+        Err(e) => {
+            // put it back so it can be tried again (or dismissed, if the message is gone already)
+            tracing::warn!("Couldn't act on report {}: {}", report_message.id, e);
+            PENDING_REPORTS.update(&handler.state, |reports| reports.insert(report_message.id.get(), report));
+            response!(ephemeral; handler, inter, "That didn't work, so the report is still open.  Error was: {}", e);
+            return Ok(());
+        },
+    };
+
+    let outcome = match &resolution {
+        ReportResolution::Deleted => "Message deleted".to_owned(),
+        ReportResolution::Warned => "Author warned".to_owned(),
+        ReportResolution::TimedOut {until} => format!("Author timed out until <t:{}:f>", until.duration_since(UNIX_EPOCH)?.as_secs()),
+        ReportResolution::Dismissed => "Report dismissed".to_owned(),
+    };
+
+    let case_kind = match &resolution {
+        ReportResolution::Deleted => Some(CaseKind::DeleteMessage),
+        ReportResolution::Warned => Some(CaseKind::Warn),
+        ReportResolution::TimedOut {..} => Some(CaseKind::Timeout),
+        ReportResolution::Dismissed => None,
+    };
+    if let Some(kind) = case_kind {
+        history::record(&handler.state, guild_id, author_id, Some(moderator_user.id), kind, format!("Reported by <@{}>: {}", report.reporter_id, report.reason));
+    }
+
+    ModLogEntry::new(moderator_user, Some(channel_id), SystemTime::now(), ModLogAction::Report {
+        reporter_id: report.reporter_id,
+        reporter_name: report.reporter_name.clone(),
+        reporter_discrim: report.reporter_discrim,
+        reason: report.reason.clone(),
+        message: report.to_modlog_message(),
+        resolution,
+    }).log(&handler.state);
+
+    if let Some(modlog_channel_id) = get_modlog_channel(&handler.state, guild_id) {
+        let builder = EmbedBuilder::new()
+            .title(format!("Member report handled: {}", outcome))
+            .description(report.content.clone())
+            .field(EmbedField {name: "Sent by".to_string(), value: format!("<@{}>", report.author_id), inline: false})
+            .field(EmbedField {name: "Reported by".to_string(), value: format!("<@{}>", report.reporter_id), inline: false})
+            .field(EmbedField {name: "Reason".to_string(), value: report.reason.clone(), inline: false})
+            .field(EmbedField {name: "Handled by".to_string(), value: format_user(moderator_user), inline: false})
+            .field(EmbedField {name: "Channel".to_string(), value: format!("<#{}>", channel_id), inline: false});
+        let _pending = handler.state.metrics.modlog_post();
+        // the report is handled either way, so don't let this stop the moderator hearing about it
+        if let Err(e) = handler.client.create_message(modlog_channel_id).embeds(&[builder.build()])?.await {
+            tracing::warn!("Couldn't post handled report to the modlog channel: {}", e);
+        }
+    }
+
+    resolve_buttons(&handler, &inter, &format!("{} by {}", outcome, format_user(moderator_user))).await?;
+
+    Ok(())
+}
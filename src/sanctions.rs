@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use twilight_interactions::command::CommandModel;
+use twilight_model::application::interaction::{application_command::CommandData, Interaction};
+use twilight_model::channel::message::embed::EmbedField;
+use twilight_model::gateway::payload::incoming::{MemberAdd, MemberUpdate};
+use twilight_model::id::Id;
+use twilight_model::guild::Member;
+use twilight_model::id::marker::{GuildMarker, RoleMarker, UserMarker};
+use twilight_util::builder::embed::EmbedBuilder;
+use twl_fw::response;
+
+use smb_log_format::ModLogAction;
+use crate::actions::{self, Action};
+use crate::business_logic::{bot_user, format_user, get_guild, get_initiating_user, is_server_admin, post_to_modlog, update_config_by, Actor};
+use crate::commands::{AddSanctionRoleCommand, DeleteSanctionRoleCommand};
+use crate::disk_log::ModLogEntryExt;
+use crate::history::{self, CaseKind};
+use crate::persist::Persisted;
+use crate::state::{BotHandler, BotState};
+
+// people leave and rejoin to get rid of mute roles and the like, since discord doesn't give you
+// your roles back when you rejoin.  so we keep track of who has a sanction role, and put it back on
+// them if they come back.
+//
+// a member showing up without a role doesn't mean they've served their sanction: that's exactly
+// what they look like after rejoining, or after a reapply that failed.  so seeing a member only
+// ever adds to what we remember, and a role is only forgotten once the audit log says someone took
+// it off them.
+
+/// (guild ID, user ID) -> the sanction roles that member has been given and not had taken away.
+/// Members who leave stay in here, which is the whole point.
+static ACTIVE_SANCTIONS: Persisted<HashMap<(u64, u64), Vec<u64>>> = Persisted::new("sanctions.msgpack");
+
+/// Every role in the guild that counts as a sanction: the ones in `sanction_roles`, plus the
+/// quarantine role.
+// kept out of the async functions below for the same reason as update_config()
+fn sanction_roles(state: &BotState, guild_id: Id<GuildMarker>) -> Vec<u64> {
+    let config = state.config.lock().unwrap();
+    let Some(guild_config) = config.get(guild_id.get().to_string().as_str()) else {
+        return Vec::new();
+    };
+    let mut roles = guild_config.get("sanction_roles")
+        .and_then(|roles| roles.as_array())
+        .map(|roles| roles.iter().filter_map(|role| role.as_integer()).map(|role| role as u64).collect::<Vec<_>>())
+        .unwrap_or_default();
+    if let Some(quarantine_role) = guild_config.get("quarantine_role_id").and_then(|role| role.as_integer()) {
+        roles.push(quarantine_role as u64);
+    }
+    roles
+}
+
+/// Remember any sanction roles these members hold that we didn't know about, given (user ID, all of
+/// their roles).
+fn note_roles(state: &BotState, guild_id: Id<GuildMarker>, members: impl IntoIterator<Item = (Id<UserMarker>, Vec<Id<RoleMarker>>)>) {
+    let sanction_roles = sanction_roles(state, guild_id);
+    let changes = ACTIVE_SANCTIONS.read(state, |sanctions| {
+        members.into_iter()
+            .map(|(user_id, roles)| {
+                let key = (guild_id.get(), user_id.get());
+                let known = sanctions.get(&key).map(Vec::as_slice).unwrap_or_default();
+                let new = roles.iter().map(|role| role.get()).filter(|role| sanction_roles.contains(role) && !known.contains(role)).collect::<Vec<_>>();
+                (key, new)
+            })
+            .filter(|(_, new)| !new.is_empty())
+            .collect::<Vec<_>>()
+    });
+    // member updates fire a lot, so only touch the disk if something actually changed
+    if changes.is_empty() {
+        return;
+    }
+    ACTIVE_SANCTIONS.update(state, |sanctions| {
+        for (key, new) in changes {
+            sanctions.entry(key).or_default().extend(new);
+        }
+    });
+}
+
+/// Called for every MEMBER_ROLE_UPDATE entry in a guild's audit log, whoever made it (the bot
+/// included, for /release).  Forgets the sanction roles it took off the member.
+pub(crate) fn on_roles_removed(state: &BotState, guild_id: Id<GuildMarker>, user_id: Id<UserMarker>, removed: &[u64]) {
+    let key = (guild_id.get(), user_id.get());
+    if !ACTIVE_SANCTIONS.read(state, |sanctions| sanctions.get(&key).is_some_and(|held| held.iter().any(|role| removed.contains(role)))) {
+        return;
+    }
+    ACTIVE_SANCTIONS.update(state, |sanctions| {
+        if let Some(held) = sanctions.get_mut(&key) {
+            held.retain(|role| !removed.contains(role));
+            if held.is_empty() {
+                sanctions.remove(&key);
+            }
+        }
+    });
+}
+
+/// Called for every GUILD_MEMBER_UPDATE, which carries the member's full list of roles.
+pub(crate) fn on_member_update(state: &BotState, update: &MemberUpdate) {
+    note_roles(state, update.guild_id, [(update.user.id, update.roles.clone())]);
+}
+
+/// Called for every GUILD_CREATE and GUILD_MEMBERS_CHUNK, so members who were sanctioned while the
+/// bot wasn't watching are known about before they get a chance to leave.
+pub(crate) fn on_members(state: &BotState, guild_id: Id<GuildMarker>, members: &[Member]) {
+    note_roles(state, guild_id, members.iter().map(|member| (member.user.id, member.roles.clone())));
+}
+
+/// Catch up on every cached member of a guild, for when its list of sanction roles changes.
+fn note_cached_members(handler: &BotHandler, guild_id: Id<GuildMarker>) {
+    let Some(user_ids) = handler.cache().guild_members(guild_id).map(|members| members.iter().copied().collect::<Vec<_>>()) else {
+        return;
+    };
+    let members = user_ids.into_iter()
+        .filter_map(|user_id| Some((user_id, handler.cache().member(guild_id, user_id)?.roles().to_vec())))
+        .collect::<Vec<_>>();
+    note_roles(&handler.state, guild_id, members);
+}
+
+/// Called for every GUILD_MEMBER_ADD.  Puts back any sanction roles the member had when they left.
+pub(crate) async fn on_member_add(handler: Arc<BotHandler>, add: MemberAdd) {
+    if let Err(e) = reapply(&handler, &add).await {
+        tracing::error!("Error reapplying sanctions to {} in {}: {}", add.member.user.id, add.guild_id, e);
+    }
+}
+
+async fn reapply(handler: &BotHandler, add: &MemberAdd) -> anyhow::Result<()> {
+    let user = &add.member.user;
+    // roles that have stopped being sanctions since are left alone
+    let sanction_roles = sanction_roles(&handler.state, add.guild_id);
+    let roles = ACTIVE_SANCTIONS.read(&handler.state, |sanctions| sanctions.get(&(add.guild_id.get(), user.id.get())).cloned())
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|role| sanction_roles.contains(role))
+        .collect::<Vec<_>>();
+    if roles.is_empty() {
+        return Ok(());
+    }
+
+    let actor = Actor::Bot(bot_user(handler)?);
+    let mut reapplied = Vec::new();
+    let mut simulated = false;
+    for role in roles.iter() {
+        let role_id: Id<RoleMarker> = Id::new(*role);
+        let action = Action::AddRole {user_id: user.id, role_id, reason: "Sanction evasion: member left and rejoined"};
+        match actions::execute(handler, add.guild_id, Some("sanction_reapply"), &actor, action).await {
+            Ok(true) => reapplied.push(*role),
+            Ok(false) => simulated = true,
+            // the role may have been deleted since
+            Err(e) => tracing::warn!("Couldn't reapply role {} to {}: {}", role_id, user.id, e),
+        }
+    }
+    // in shadow mode the simulated actions have already been posted, and nothing really happened
+    if simulated && reapplied.is_empty() {
+        return Ok(());
+    }
+
+    let role_list = reapplied.iter().map(|id| format!("<@&{}>", id)).collect::<Vec<_>>().join(" ");
+    actor.log_entry(None, ModLogAction::SanctionReapplied {
+        user_id: user.id.get(),
+        roles: reapplied.clone(),
+    }).log(&handler.state);
+    history::record(&handler.state, add.guild_id, user.id, None, CaseKind::SanctionEvasion, format!("Rejoined with active sanctions.  Reapplied {}", role_list));
+
+    let builder = EmbedBuilder::new()
+        .title("Sanction evasion")
+        .description("This member left while under a sanction and has rejoined.  Their sanction roles have been put back.")
+        .field(EmbedField {name: "Member".to_string(), value: format_user(user), inline: false})
+        .field(EmbedField {name: "Roles reapplied".to_string(), value: if role_list.is_empty() {"None".to_owned()} else {role_list}, inline: false});
+    post_to_modlog(handler, add.guild_id, builder.build()).await?;
+    Ok(())
+}
+
+pub(crate) async fn add_sanction_role(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+    if !is_server_admin(&handler, &inter) {
+        response!(ephemeral; handler, inter, "You need the Manage Server permission to change the bot's settings.");
+        return Ok(());
+    }
+
+    let cmd = AddSanctionRoleCommand::from_interaction(data.into())?;
+    let role_id = cmd.role.get();
+
+    let mut message = String::new();
+
+    update_config_by(&handler, moderator_user, guild_id, |guild_config| {
+        let Some(sanction_roles) = guild_config.as_table_mut().and_then(|table| table.entry("sanction_roles").or_insert(toml_edit::value(toml_edit::Array::new())).as_array_mut()) else {
+            message = "Config file is not in a valid format.  No changes were made.".into();
+            return;
+        };
+        if sanction_roles.iter().any(|s| s.as_integer().is_some_and(|val| val==role_id as i64)) {
+            message = "That role is already a sanction role.".into();
+        } else {
+            sanction_roles.push(role_id as i64);
+            message = format!("<@&{}> will now be put back on members who leave and rejoin.", role_id);
+        }
+    }).await?;
+    note_cached_members(&handler, guild_id);
+
+    response!(ephemeral; handler, inter, "{}", message);
+    Ok(())
+}
+
+pub(crate) async fn del_sanction_role(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+    let moderator_user = get_initiating_user(&inter)?;
+    if !is_server_admin(&handler, &inter) {
+        response!(ephemeral; handler, inter, "You need the Manage Server permission to change the bot's settings.");
+        return Ok(());
+    }
+
+    let cmd = DeleteSanctionRoleCommand::from_interaction(data.into())?;
+    let role_id = cmd.role.get();
+
+    let mut message = String::new();
+
+    update_config_by(&handler, moderator_user, guild_id, |guild_config| {
+        let Some(sanction_roles) = guild_config.as_table_mut().and_then(|table| table.entry("sanction_roles").or_insert(toml_edit::value(toml_edit::Array::new())).as_array_mut()) else {
+            message = "Config file is not in a valid format.  No changes were made.".into();
+            return;
+        };
+        let pos = sanction_roles.iter().position(|s| s.as_integer().is_some_and(|val| val==role_id as i64));
+        if let Some(idx) = pos {
+            sanction_roles.remove(idx);
+            message = format!("<@&{}> is no longer a sanction role.", role_id);
+        } else {
+            message = "That role is already not a sanction role.".into();
+        }
+    }).await?;
+    note_cached_members(&handler, guild_id);
+
+    response!(ephemeral; handler, inter, "{}", message);
+    Ok(())
+}
@@ -0,0 +1,179 @@
+use std::any::Any;
+use std::collections::HashMap;
+use std::fs::File;
+use std::future::Future;
+use std::ops::Deref;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use once_cell::sync::OnceCell;
+use tokio::sync::{watch, Notify};
+use tokio::task::JoinHandle;
+use tracing::Instrument;
+use twilight_model::id::Id;
+use twilight_model::id::marker::UserMarker;
+use twl_fw::InteractionHandler;
+
+use crate::metrics::Metrics;
+
+// everything one running bot owns: its config, where it keeps its files, its modlog file and the
+// persisted stores.  nothing in here is global, so any number of bots (or tests) can run in one
+// process without seeing each other's state.
+//
+// twl_fw's InteractionHandler has no room for state of our own, so everything the bot does is
+// handed a BotHandler instead, which is the InteractionHandler and the BotState together.  code
+// that only needs the state, like the config helpers and the persisted stores, takes a &BotState.
+// either way the state is always passed in, never looked up, so there's no way to end up running
+// without it.
+//
+// each task holds a reference to the state, so it's freed once the bot and everything it started
+// have finished.
+
+pub(crate) struct BotState {
+    /// The profile name, to tell bots apart in the logs and metrics.
+    pub(crate) name: String,
+    pub(crate) config: Mutex<toml_edit::Document>,
+    pub(crate) config_path: PathBuf,
+    pub(crate) output_path: PathBuf,
+    /// Filled in once the bot has signed into Discord.
+    pub(crate) handler: OnceCell<Arc<InteractionHandler>>,
+    pub(crate) metrics: Metrics,
+    /// Filled in once we've asked Discord who owns the application.
+    pub(crate) owners: OnceCell<Vec<Id<UserMarker>>>,
+    pub(crate) logfile: Option<Mutex<File>>,
+    /// Whether timers from before the bot last restarted have been picked back up yet.
+    pub(crate) resumed: AtomicBool,
+    /// Set to true to shut the bot down.
+    pub(crate) shutdown: watch::Sender<bool>,
+    /// How many tasks started with spawn() haven't finished yet.
+    in_flight: AtomicUsize,
+    idle: Notify,
+    /// The value of each Persisted, by file name.  Loaded the first time it's used.
+    pub(crate) stores: Mutex<HashMap<&'static str, Arc<dyn Any + Send + Sync>>>,
+}
+
+impl BotState {
+    /// Set up a bot's state.
+    pub(crate) fn new(name: String, config: toml_edit::Document, config_path: PathBuf, output_path: PathBuf) -> Arc<Self> {
+        let logfile = std::fs::OpenOptions::new().create(true).truncate(false).append(true).open(output_path.join("modlog.ndjson"));
+        if let Err(e) = &logfile {
+            tracing::warn!("Unable to open logfile! Error message was: {}  Modlog will be written to Discord only!", e);
+        }
+        Arc::new(Self {
+            name,
+            config: Mutex::new(config),
+            config_path,
+            output_path,
+            handler: OnceCell::new(),
+            metrics: Metrics::default(),
+            owners: OnceCell::new(),
+            logfile: logfile.ok().map(Mutex::new),
+            resumed: AtomicBool::new(false),
+            shutdown: watch::channel(false).0,
+            in_flight: AtomicUsize::new(0),
+            idle: Notify::new(),
+            stores: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Wait for every task started with spawn() to finish, for up to `timeout`.  Returns how many
+    /// were still running when it gave up.
+    pub(crate) async fn drain(&self, timeout: Duration) -> usize {
+        let _ = tokio::time::timeout(timeout, async {
+            loop {
+                let idle = self.idle.notified();
+                if self.in_flight.load(Ordering::SeqCst) == 0 {
+                    return;
+                }
+                idle.await;
+            }
+        }).await;
+        self.in_flight.load(Ordering::SeqCst)
+    }
+
+    /// tokio::spawn, but the new task logs under this bot's name, and shutting down waits for it
+    /// to finish, so this is for work that shouldn't be cut off halfway.
+    pub(crate) fn spawn<F>(self: &Arc<Self>, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let in_flight = InFlight::new(self.clone());
+        tokio::spawn(async move {
+            let _in_flight = in_flight;
+            future.await
+        }.in_current_span())
+    }
+
+    /// Like spawn(), but shutting down doesn't wait for it.  For timers, which might not go off for
+    /// days and are picked back up when the bot starts again anyway.
+    pub(crate) fn spawn_untracked<F>(&self, future: F) -> JoinHandle<F::Output>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        tokio::spawn(future.in_current_span())
+    }
+}
+
+/// Counts a task as in flight until it's dropped.
+struct InFlight(Arc<BotState>);
+
+impl InFlight {
+    fn new(state: Arc<BotState>) -> Self {
+        state.in_flight.fetch_add(1, Ordering::SeqCst);
+        Self(state)
+    }
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if self.0.in_flight.fetch_sub(1, Ordering::SeqCst) == 1 {
+            self.0.idle.notify_waiters();
+        }
+    }
+}
+
+/// What commands, components and event handlers are handed: twl_fw's InteractionHandler, which
+/// it derefs to, plus the state of the bot it belongs to.
+pub(crate) struct BotHandler {
+    twl: Arc<InteractionHandler>,
+    pub(crate) state: Arc<BotState>,
+}
+
+/// Every BotHandler that's been made, so a command that twl_fw calls with only its own
+/// InteractionHandler can be given the rest.
+static HANDLERS: Mutex<Vec<Weak<BotHandler>>> = Mutex::new(Vec::new());
+
+impl BotHandler {
+    pub(crate) fn new(twl: Arc<InteractionHandler>, state: Arc<BotState>) -> Arc<Self> {
+        let _ = state.handler.set(twl.clone());
+        let handler = Arc::new(Self { twl, state });
+        let mut handlers = HANDLERS.lock().unwrap();
+        handlers.retain(|handler| handler.strong_count() > 0);
+        handlers.push(Arc::downgrade(&handler));
+        handler
+    }
+
+    /// The BotHandler wrapping a twl_fw InteractionHandler, if it's still around.
+    pub(crate) fn find(twl: &Arc<InteractionHandler>) -> Option<Arc<Self>> {
+        HANDLERS.lock().unwrap().iter()
+            .filter_map(Weak::upgrade)
+            .find(|handler| Arc::ptr_eq(&handler.twl, twl))
+    }
+
+    /// The twl_fw InteractionHandler on its own, for handing interactions to it.
+    pub(crate) fn twl(&self) -> Arc<InteractionHandler> {
+        self.twl.clone()
+    }
+}
+
+impl Deref for BotHandler {
+    type Target = InteractionHandler;
+
+    fn deref(&self) -> &InteractionHandler {
+        &self.twl
+    }
+}
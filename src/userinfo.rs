@@ -0,0 +1,163 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use twilight_model::application::interaction::{application_command::CommandData, Interaction};
+use twilight_model::channel::message::embed::EmbedField;
+use twilight_model::gateway::payload::incoming::AutoModerationActionExecution;
+use twilight_model::id::Id;
+use twilight_model::id::marker::{RoleMarker, UserMarker};
+use twilight_util::builder::embed::EmbedBuilder;
+use twl_fw::response;
+
+use crate::business_logic::{get_guild, is_allowed_to_use, respond_with_embed};
+use crate::history::{self, Case, CaseKind};
+use crate::persist::Scratch;
+use crate::state::{BotHandler, BotState};
+
+/// Discord snowflakes count milliseconds from the start of 2015.
+const DISCORD_EPOCH_MS: u64 = 1420070400000;
+
+/// Embed field values can't be longer than this.
+const MAX_FIELD_LENGTH: usize = 1024;
+
+const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// How long after an automod hit more events for the same rule, member and content are taken to be
+/// the same hit.
+const AUTOMOD_HIT_WINDOW: Duration = Duration::from_secs(10);
+
+/// (guild ID, rule ID, user ID, content) -> when we last recorded it
+static RECENT_AUTOMOD_HITS: Scratch<HashMap<(u64, u64, u64, String), SystemTime>> = Scratch::new("recent_automod_hits");
+
+/// Called when Discord's own automod blocks or flags something.  We don't act on it, just remember
+/// it so it shows up in the member's history and risk score.
+pub(crate) fn on_automod_action(state: &BotState, event: &AutoModerationActionExecution) {
+    // Discord sends one of these per action the rule takes, so blocking a message, alerting the
+    // moderators and timing the author out is three events for one hit.  a blocked message has no
+    // message ID and only the alert has an alert message ID, so the only thing they all share is
+    // the rule, the member and what they wrote.
+    let now = SystemTime::now();
+    let key = (event.guild_id.get(), event.rule_id.get(), event.user_id.get(), event.content.clone());
+    let repeat = RECENT_AUTOMOD_HITS.update(state, |hits| {
+        hits.retain(|_, seen| now.duration_since(*seen).is_ok_and(|age| age < AUTOMOD_HIT_WINDOW));
+        hits.insert(key, now).is_some()
+    });
+    if repeat {
+        return;
+    }
+
+    let detail = match &event.matched_keyword {
+        Some(keyword) => format!("Matched \"{}\"", keyword),
+        None => "Triggered an automod rule".to_owned(),
+    };
+    history::record(state, event.guild_id, event.user_id, None, CaseKind::Automod, detail);
+}
+
+pub(crate) async fn user_info(handler: Arc<BotHandler>, inter: Interaction, data: CommandData) -> anyhow::Result<()> {
+    let guild_id = get_guild(&inter, &data)?;
+
+    if !inter.member.as_ref().is_some_and(|member| is_allowed_to_use(&handler, member, guild_id, "User info")) {
+        response!(ephemeral; handler, inter, "You do not have permission to use that command.");
+        return Ok(());
+    }
+
+    let user_id: Id<UserMarker> = data.target_id.unwrap().cast();
+    let resolved = data.resolved.unwrap();
+
+    let created = created_at(user_id);
+    // the interaction carries the member's join date and roles for us.  fall back to the cache in
+    // case they're missing for some reason.
+    let (joined, roles): (Option<SystemTime>, Vec<Id<RoleMarker>>) = match resolved.members.get(&user_id) {
+        Some(member) => (Some(timestamp_to_system_time(member.joined_at.as_secs())), member.roles.clone()),
+        None => match handler.cache().member(guild_id, user_id) {
+            Some(member) => (member.joined_at().map(|t| timestamp_to_system_time(t.as_secs())), member.roles().to_vec()),
+            None => (None, Vec::new()),
+        },
+    };
+
+    let cases = history::cases(&handler.state, guild_id, user_id);
+    let (score, reasons) = risk_score(created, joined, roles.is_empty(), &cases);
+
+    let count = |kind| cases.iter().filter(|case| case.kind == kind).count();
+    let summary = format!(
+        "{} bans, {} timeouts, {} warnings, {} deleted messages, {} notes",
+        count(CaseKind::Ban), count(CaseKind::Timeout), count(CaseKind::Warn), count(CaseKind::DeleteMessage), count(CaseKind::Note),
+    );
+    let roles = if roles.is_empty() {
+        "None".to_owned()
+    } else {
+        roles.iter().map(|role| format!("<@&{}>", role)).collect::<Vec<_>>().join(" ")
+    };
+    let name = resolved.users.get(&user_id).map(|user| user.name.clone()).unwrap_or_else(|| user_id.to_string());
+
+    let embed = EmbedBuilder::new()
+        .title(format!("User info: {}", name))
+        .description(format!("<@{}>", user_id))
+        .field(EmbedField {name: "Account created".to_string(), value: relative_time(created), inline: true})
+        .field(EmbedField {name: "Joined server".to_string(), value: joined.map(relative_time).unwrap_or_else(|| "Unknown".to_owned()), inline: true})
+        .field(EmbedField {name: "Roles".to_string(), value: roles, inline: false})
+        .field(EmbedField {name: "Prior cases".to_string(), value: summary, inline: false})
+        .field(EmbedField {name: "Automod hits".to_string(), value: count(CaseKind::Automod).to_string(), inline: true})
+        .field(EmbedField {name: "Risk".to_string(), value: format!("{} ({})\n{}", risk_label(score), score, reasons.join("\n")), inline: false})
+        .field(EmbedField {name: "Most recent".to_string(), value: or_none(history::format_cases(cases.iter().rev().take(5), MAX_FIELD_LENGTH)), inline: false})
+        .build();
+    respond_with_embed(&handler, &inter, embed).await
+}
+
+/// A rough tally of how worried a moderator should be about someone, along with the reasons for
+/// it.  The weights are guesses, not science.
+fn risk_score(created: SystemTime, joined: Option<SystemTime>, no_roles: bool, cases: &[Case]) -> (u32, Vec<String>) {
+    let mut score = 0;
+    let mut reasons = Vec::new();
+    let age = |t: SystemTime| SystemTime::now().duration_since(t).unwrap_or_default();
+
+    if age(created) < 7 * DAY {
+        score += 3;
+        reasons.push("Account is less than a week old".to_owned());
+    } else if age(created) < 30 * DAY {
+        score += 1;
+        reasons.push("Account is less than a month old".to_owned());
+    }
+    if joined.is_some_and(|joined| age(joined) < DAY) {
+        score += 2;
+        reasons.push("Joined in the last day".to_owned());
+    }
+    if no_roles {
+        score += 1;
+        reasons.push("Has no roles".to_owned());
+    }
+    for (kind, weight) in [(CaseKind::Ban, 5), (CaseKind::Timeout, 3), (CaseKind::Warn, 2), (CaseKind::DeleteMessage, 1), (CaseKind::Automod, 1)] {
+        let n = cases.iter().filter(|case| case.kind == kind).count() as u32;
+        if n > 0 {
+            score += n * weight;
+            reasons.push(format!("{} prior case(s) of {}", n, kind.label()));
+        }
+    }
+    (score, reasons)
+}
+
+fn risk_label(score: u32) -> &'static str {
+    match score {
+        0..=2 => "Low",
+        3..=6 => "Medium",
+        _ => "High",
+    }
+}
+
+pub(crate) fn created_at(user_id: Id<UserMarker>) -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis((user_id.get() >> 22) + DISCORD_EPOCH_MS)
+}
+
+fn timestamp_to_system_time(secs: i64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(secs.max(0) as u64)
+}
+
+fn relative_time(t: SystemTime) -> String {
+    let secs = t.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    format!("<t:{}:D> (<t:{}:R>)", secs, secs)
+}
+
+fn or_none(s: String) -> String {
+    if s.is_empty() {"None".to_owned()} else {s}
+}